@@ -1,22 +1,35 @@
+use std::fmt::Debug;
+
+use rust_atomics_and_locks::concurrent_map::{ConcurrentMap, PinnedMap};
+use rust_atomics_and_locks::sharded_map::ShardedMap;
+
 fn main() {
-    use papaya::HashMap;
+    // The same code, on papaya's map and on the sharded one.
+    run::<papaya::HashMap<char, i32>, papaya::HashMap<&str, i32>>();
+    run::<ShardedMap<char, i32>, ShardedMap<&str, i32>>();
+}
 
+fn run<M, N>()
+where
+    M: ConcurrentMap<char, i32> + Default + Debug + Sync,
+    N: ConcurrentMap<&'static str, i32> + Default + Debug,
+{
     // Create a map.
-    let map = HashMap::new();
+    let map = M::default();
 
     // Pin the map.
-    let map = map.pin();
+    let pinned = map.pin();
 
     // Use the map as normal.
-    map.insert('A', 1);
-    assert_eq!(map.get(&'A'), Some(&1));
-    assert_eq!(map.len(), 1);
+    pinned.insert('A', 1);
+    assert_eq!(pinned.get(&'A').as_deref(), Some(&1));
+    assert_eq!(pinned.len(), 1);
 
     println!("{:?}", map);
 
 
     // Use a map from multiple threads.
-    let map = HashMap::new();
+    let map = M::default();
     std::thread::scope(|s| {
         // Insert some values.
         s.spawn(|| {
@@ -28,9 +41,9 @@ fn main() {
 
         // Read the values.
         s.spawn(|| {
-            for (key, value) in map.pin().iter() {
+            map.pin().for_each(|key, value| {
                 println!("{key}: {value}");
-            }
+            });
         });
 
         // Remove the values.
@@ -45,14 +58,14 @@ fn main() {
     });
 
 
-    let map1 = HashMap::new();
-    let map1 = map1.pin();
-    map1.insert("poney", 42);
-    map1.update_or_insert("poney", |e| e+1, 42);
+    let map1 = N::default();
+    let pinned = map1.pin();
+    pinned.insert("poney", 42);
+    pinned.update_or_insert("poney", |e| e+1, 42);
     println!("{:?}", map1);
 
 
-    let map = HashMap::new();
+    let map = N::default();
     assert_eq!(*map.pin().update_or_insert_with("a", |i| i + 1, || 0), 0);
     assert_eq!(*map.pin().update_or_insert_with("a", |i| i + 1, || 0), 1);
 
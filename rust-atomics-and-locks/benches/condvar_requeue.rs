@@ -0,0 +1,99 @@
+//! `Condvar::notify_all` with 64 waiting threads, with and without
+//! requeueing them onto the mutex instead of waking them all up.
+//!
+//! Run with `cargo bench --bench condvar_requeue`.
+
+use std::sync::atomic::AtomicU32;
+use std::thread;
+use std::time::{Duration, Instant};
+use rust_atomics_and_locks::ch9_locks::condvar_2::Condvar;
+use rust_atomics_and_locks::ch9_locks::mutex_3::Mutex;
+use rust_atomics_and_locks::ch9_locks::wait_strategy::{SpinThenFutex, WaitStrategy};
+
+const WAITERS: u32 = 64;
+const ROUNDS: u32 = 200;
+
+/// Exactly `SpinThenFutex`, but without `USES_FUTEX`, so the condition variable can't requeue.
+#[derive(Clone, Copy)]
+struct NoRequeue(SpinThenFutex);
+
+impl WaitStrategy for NoRequeue {
+    fn spin(&self, busy: impl FnMut() -> bool) {
+        self.0.spin(busy)
+    }
+
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+        self.0.wait(a, expected, deadline)
+    }
+
+    fn wake_one(&self, a: &AtomicU32) {
+        self.0.wake_one(a)
+    }
+
+    fn wake_all(&self, a: &AtomicU32) {
+        self.0.wake_all(a)
+    }
+}
+
+/// Every round, wakes up all waiters, and waits until each of them has seen it.
+fn run<W: WaitStrategy + Sync>(strategy: W) -> Duration {
+    #[derive(Default)]
+    struct State {
+        round: u32,
+        done: u32,
+    }
+
+    let state = Mutex::with_strategy(State::default(), strategy);
+    let next_round = Condvar::new();
+    let all_done = Condvar::new();
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..WAITERS {
+            s.spawn(|| {
+                for round in 1..=ROUNDS {
+                    let mut g = next_round.wait_while(state.lock().unwrap(), |s| s.round < round).unwrap();
+                    g.done += 1;
+                    if g.done == WAITERS * round {
+                        all_done.notify_one();
+                    }
+                }
+            });
+        }
+        for round in 1..=ROUNDS {
+            let mut g = state.lock().unwrap();
+            g.round = round;
+            next_round.notify_all();
+            drop(all_done.wait_while(g, |s| s.done < WAITERS * round).unwrap());
+        }
+    });
+    start.elapsed()
+}
+
+/// Voluntary and involuntary context switches of all threads in this process so far.
+#[cfg(target_os = "linux")]
+fn context_switches() -> i64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    usage.ru_nvcsw as i64 + usage.ru_nivcsw as i64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn context_switches() -> i64 {
+    0
+}
+
+fn main() {
+    fn report(name: &str, run: impl FnOnce() -> Duration) {
+        let before = context_switches();
+        let time = run();
+        let switches = context_switches() - before;
+        println!(
+            "{name:>10}: {time:>10.2?} total, {:>8.2?} per round, {:>6} context switches per round",
+            time / ROUNDS,
+            switches / ROUNDS as i64,
+        );
+    }
+
+    report("wake all", || run(NoRequeue(SpinThenFutex::new(100))));
+    report("requeue", || run(SpinThenFutex::new(100)));
+}
@@ -0,0 +1,222 @@
+//! The spin locks from chapter 4, the mutexes and reader-writer locks from chapter 9,
+//! and their `std::sync` equivalents, uncontended, contended by 2 to 16 threads,
+//! and with mostly readers.
+//!
+//! Run with `cargo bench --bench locks`, or for example `cargo bench --bench locks -- rwlock`
+//! for only some of them. Apart from criterion's own reports, the results of every run are
+//! collected in `target/criterion/locks.json`, to compare them between versions.
+
+use std::fs;
+use std::hint::black_box;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use criterion::{BenchmarkId, Criterion, Throughput};
+use rust_atomics_and_locks::ch4_spin_lock::{s3_guard, s4_ticket, s5_mcs};
+use rust_atomics_and_locks::ch9_locks::{mutex_1, mutex_2, mutex_3, rwlock_1, rwlock_2, rwlock_3};
+
+const THREADS: [u64; 4] = [2, 4, 8, 16];
+
+/// A lock around a counter, so all kinds of locks can run the same benchmarks.
+trait Lock: Sync {
+    const NAME: &'static str;
+    /// Whether it's a spin lock, which gets very slow with more threads than cores.
+    const SPIN: bool;
+
+    fn new() -> Self;
+
+    fn write(&self, f: impl FnOnce(&mut u64));
+
+    /// Locks that can't be shared lock exclusively for reading too.
+    fn read(&self, f: impl FnOnce(&u64)) {
+        self.write(|v| f(v));
+    }
+}
+
+macro_rules! lock {
+    (spin: $($rest:tt)*) => { lock!(@ true, $($rest)*); };
+    (@ $spin:expr, $name:literal, $t:ty, |$l:ident| $write:expr $(, $read:expr)?) => {
+        impl Lock for $t {
+            const NAME: &'static str = $name;
+            const SPIN: bool = $spin;
+
+            fn new() -> Self {
+                <$t>::new(0)
+            }
+
+            fn write(&self, f: impl FnOnce(&mut u64)) {
+                let $l = self;
+                f(&mut *$write);
+            }
+
+            $(
+                fn read(&self, f: impl FnOnce(&u64)) {
+                    let $l = self;
+                    f(&*$read);
+                }
+            )?
+        }
+    };
+    ($($rest:tt)*) => { lock!(@ false, $($rest)*); };
+}
+
+lock!(spin: "s3_guard", s3_guard::SpinLock<u64>, |l| l.lock());
+lock!(spin: "s4_ticket", s4_ticket::SpinLock<u64>, |l| l.lock());
+lock!(spin: "s5_mcs", s5_mcs::SpinLock<u64>, |l| l.lock());
+lock!("mutex_1", mutex_1::Mutex<u64>, |l| l.lock());
+lock!("mutex_2", mutex_2::Mutex<u64>, |l| l.lock());
+lock!("mutex_3", mutex_3::Mutex<u64>, |l| l.lock().unwrap());
+lock!("std_mutex", std::sync::Mutex<u64>, |l| l.lock().unwrap());
+lock!("rwlock_1", rwlock_1::RwLock<u64>, |l| l.write(), l.read());
+lock!("rwlock_2", rwlock_2::RwLock<u64>, |l| l.write(), l.read());
+lock!("rwlock_3", rwlock_3::RwLock<u64>, |l| l.write().unwrap(), l.read().unwrap());
+lock!("std_rwlock", std::sync::RwLock<u64>, |l| l.write().unwrap(), l.read().unwrap());
+
+/// Calls `f` for all locks, or only for the reader-writer locks.
+macro_rules! for_each_lock {
+    ($f:ident($($arg:expr),*)) => {
+        for_each_lock!(rwlocks: $f($($arg),*));
+        $f::<s3_guard::SpinLock<u64>>($($arg),*);
+        $f::<s4_ticket::SpinLock<u64>>($($arg),*);
+        $f::<s5_mcs::SpinLock<u64>>($($arg),*);
+        $f::<mutex_1::Mutex<u64>>($($arg),*);
+        $f::<mutex_2::Mutex<u64>>($($arg),*);
+        $f::<mutex_3::Mutex<u64>>($($arg),*);
+        $f::<std::sync::Mutex<u64>>($($arg),*);
+    };
+    (rwlocks: $f:ident($($arg:expr),*)) => {
+        $f::<rwlock_1::RwLock<u64>>($($arg),*);
+        $f::<rwlock_2::RwLock<u64>>($($arg),*);
+        $f::<rwlock_3::RwLock<u64>>($($arg),*);
+        $f::<std::sync::RwLock<u64>>($($arg),*);
+    };
+}
+
+fn uncontended<L: Lock>(c: &mut Criterion) {
+    let lock = L::new();
+    c.benchmark_group("uncontended").bench_function(L::NAME, |b| {
+        b.iter(|| black_box(&lock).write(|v| *v += 1))
+    });
+}
+
+/// `threads` threads that each lock it once per iteration, for writing,
+/// except that only one in every `writes_every` locks is for writing
+/// and the others are for reading.
+///
+/// Spin locks are skipped with more threads than cores, where they'd
+/// mostly spin away the time slices of threads that could unlock them.
+fn contended<L: Lock>(c: &mut Criterion, group: &str, writes_every: u64) {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get() as u64);
+    let mut g = c.benchmark_group(group);
+    // Every sample spawns its own threads, so not too many of them.
+    g.sample_size(30);
+    for threads in THREADS.into_iter().filter(|&t| !L::SPIN || t <= cores) {
+        // Counted per lock operation, by all threads together.
+        g.throughput(Throughput::Elements(threads));
+        g.bench_with_input(BenchmarkId::new(L::NAME, threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                let lock = L::new();
+                let barrier = Barrier::new(threads as usize + 1);
+                thread::scope(|s| {
+                    for _ in 0..threads {
+                        s.spawn(|| {
+                            barrier.wait();
+                            for i in 0..iters {
+                                if i % writes_every == 0 {
+                                    lock.write(|v| *v += 1);
+                                } else {
+                                    lock.read(|v| {
+                                        black_box(*v);
+                                    });
+                                }
+                            }
+                        });
+                    }
+                    barrier.wait();
+                    Instant::now()
+                })
+                .elapsed()
+            })
+        });
+    }
+    g.finish();
+}
+
+/// Where criterion puts its results by default, unless the target
+/// directory is configured somewhere other than in the environment.
+fn output_directory() -> PathBuf {
+    if let Some(dir) = std::env::var_os("CRITERION_HOME") {
+        return dir.into();
+    }
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    Path::new(&target).join("criterion")
+}
+
+/// Collects the results from this run into one JSON file: an array with, for every benchmark,
+/// `{"benchmark": ..., "estimates": ...}`, with criterion's own `benchmark.json` (the name)
+/// and `estimates.json` (the mean, median, and so on, in nanoseconds per iteration) as they are.
+///
+/// Returns `None` if there were no results, such as under `cargo test`.
+fn write_json(dir: &Path, groups: &[&str], since: SystemTime) -> io::Result<Option<PathBuf>> {
+    fn find(dir: &Path, since: SystemTime, results: &mut Vec<String>) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            // Criterion also keeps the results of the previous run, in `base`.
+            if entry.file_name() != "new" {
+                find(&path, since, results)?;
+                continue;
+            }
+            let estimates = path.join("estimates.json");
+            if fs::metadata(&estimates)?.modified()? >= since {
+                let benchmark = fs::read_to_string(path.join("benchmark.json"))?;
+                let estimates = fs::read_to_string(estimates)?;
+                results.push(format!("{{\"benchmark\":{benchmark},\"estimates\":{estimates}}}"));
+            }
+        }
+        Ok(())
+    }
+
+    let mut results = Vec::new();
+    for group in groups {
+        let dir = dir.join(group);
+        if dir.exists() {
+            find(&dir, since, &mut results)?;
+        }
+    }
+    if results.is_empty() {
+        return Ok(None);
+    }
+    let path = dir.join("locks.json");
+    fs::write(&path, format!("[\n{}\n]\n", results.join(",\n")))?;
+    Ok(Some(path))
+}
+
+fn main() {
+    let start = SystemTime::now();
+    let dir = output_directory();
+    let mut c = Criterion::default()
+        .output_directory(&dir)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(2))
+        .configure_from_args();
+
+    for_each_lock!(uncontended(&mut c));
+    for_each_lock!(contended(&mut c, "contended", 1));
+    for_each_lock!(rwlocks: contended(&mut c, "read_heavy_90", 10));
+    for_each_lock!(rwlocks: contended(&mut c, "read_heavy_99", 100));
+    c.final_summary();
+
+    let groups = ["uncontended", "contended", "read_heavy_90", "read_heavy_99"];
+    match write_json(&dir, &groups, start) {
+        Ok(Some(path)) => println!("Results written to {}", path.display()),
+        Ok(None) => {}
+        Err(e) => eprintln!("Couldn't write the results to JSON: {e}"),
+    }
+}
@@ -5,17 +5,26 @@ use std::sync::atomic::Ordering::Relaxed;
 use std::thread;
 use std::time::Duration;
 
-pub fn wait(a: &AtomicU32, expected: u32) {
+/// Returns false if the timeout elapsed.
+pub fn wait(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let timespec = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs() as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
     // Refer to the futex (2) man page for the syscall signature.
-    unsafe {
+    let r = unsafe {
         libc::syscall(
             libc::SYS_futex, // The futex syscall.
             a as *const AtomicU32, // The atomic to operate on.
             libc::FUTEX_WAIT, // The futex operation.
             expected, // The expected value.
-            std::ptr::null::<libc::timespec>(), // No timeout.
-        );
-    }
+            match &timespec { // The (relative) timeout, if any.
+                Some(t) => t as *const libc::timespec,
+                None => std::ptr::null(),
+            },
+        )
+    };
+    !(r == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
 }
 
 pub fn wake_one(a: &AtomicU32) {
@@ -42,7 +51,9 @@ fn main() {
 
         println!("Waiting...");
         while a.load(Relaxed) == 0 {
-            wait(&a, 0);
+            if !wait(&a, 0, Some(Duration::from_secs(1))) {
+                println!("Still waiting...");
+            }
         }
         println!("Done!");
     });
@@ -0,0 +1,29 @@
+use std::ops::{Deref, DerefMut};
+
+/// Aligns a value to (a pair of) cache lines, so it never shares
+/// a cache line with anything else and can't suffer from false sharing.
+///
+/// 128 rather than 64 bytes, since modern x86-64 cores prefetch cache lines in
+/// pairs, and some ARM cores (e.g. Apple's) have 128 byte cache lines.
+#[repr(align(128))]
+#[derive(Default)]
+pub(crate) struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
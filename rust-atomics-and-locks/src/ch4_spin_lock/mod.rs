@@ -1,3 +1,5 @@
 pub mod s1_minimal;
 pub mod s2_unsafe;
 pub mod s3_guard;
+pub mod s4_ticket;
+pub mod s5_mcs;
@@ -1,18 +1,21 @@
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::hint;
 
 pub struct SpinLock {
     locked: AtomicBool,
 }
 
 impl SpinLock {
-    pub const fn new() -> Self {
-        Self { locked: AtomicBool::new(false) }
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self { locked: AtomicBool::new(false) }
+        }
     }
 
     pub fn lock(&self) {
         while self.locked.swap(true, Acquire) {
-            std::hint::spin_loop();
+            hint::spin_loop();
         }
     }
 
@@ -20,3 +23,39 @@ impl SpinLock {
         self.locked.store(false, Release);
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc, UnsafeCell};
+
+    struct Counter {
+        lock: SpinLock,
+        value: UnsafeCell<u32>,
+    }
+
+    unsafe impl Sync for Counter {}
+
+    impl Counter {
+        fn increment(&self) {
+            self.lock.lock();
+            unsafe { *self.value.get() += 1 };
+            self.lock.unlock();
+        }
+    }
+
+    // No preemptions, so a thread only locks once the other has unlocked: under loom, a
+    // spinning `swap` could keep reading its own swap instead of the unlocking store.
+    // (See `crate::sync`.) That still checks that the second thread sees the first increment.
+    crate::sync::model_bounded(0, || {
+        let c = Arc::new(Counter { lock: SpinLock::new(), value: UnsafeCell::new(0) });
+        let t = thread::spawn({
+            let c = c.clone();
+            move || c.increment()
+        });
+        c.increment();
+        t.join().unwrap();
+        c.lock.lock();
+        assert_eq!(unsafe { *c.value.get() }, 2);
+    });
+}
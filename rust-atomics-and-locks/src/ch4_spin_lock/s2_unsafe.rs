@@ -1,6 +1,7 @@
-use std::cell::UnsafeCell;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::UnsafeCell;
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::hint;
 
 pub struct SpinLock<T> {
     locked: AtomicBool,
@@ -10,16 +11,18 @@ pub struct SpinLock<T> {
 unsafe impl<T> Sync for SpinLock<T> where T: Send {}
 
 impl<T> SpinLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            locked: AtomicBool::new(false),
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                locked: AtomicBool::new(false),
+                value: UnsafeCell::new(value),
+            }
         }
     }
 
     pub fn lock(&self) -> &mut T {
         while self.locked.swap(true, Acquire) {
-            std::hint::spin_loop();
+            hint::spin_loop();
         }
         unsafe { &mut *self.value.get() }
     }
@@ -30,3 +33,22 @@ impl<T> SpinLock<T> {
         self.locked.store(false, Release);
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    // Bounded to 0, like `s1_minimal`.
+    crate::sync::check_lock_bounded(
+        0,
+        || SpinLock::new(0),
+        |l| {
+            *l.lock() += 1;
+            unsafe { l.unlock() };
+        },
+        |l| {
+            let v = *l.lock();
+            unsafe { l.unlock() };
+            v
+        },
+    );
+}
@@ -1,10 +1,13 @@
 use std::ops::{Deref, DerefMut};
-use std::cell::UnsafeCell;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::UnsafeCell;
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Release};
+use crate::lock_order::{Access, LockNode};
+use crate::sync::hint;
 
 pub struct SpinLock<T> {
     locked: AtomicBool,
+    node: LockNode,
     value: UnsafeCell<T>,
 }
 
@@ -17,17 +20,23 @@ pub struct Guard<'a, T> {
 unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
 
 impl<T> SpinLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            locked: AtomicBool::new(false),
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                locked: AtomicBool::new(false),
+                node: LockNode::new(),
+                value: UnsafeCell::new(value),
+            }
         }
     }
 
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
     pub fn lock(&self) -> Guard<T> {
+        self.node.before_lock(Access::Exclusive);
         while self.locked.swap(true, Acquire) {
-            std::hint::spin_loop();
+            hint::spin_loop();
         }
+        self.node.locked(Access::Exclusive);
         Guard { lock: self }
     }
 }
@@ -51,6 +60,7 @@ impl<T> DerefMut for Guard<'_, T> {
 
 impl<T> Drop for Guard<'_, T> {
     fn drop(&mut self) {
+        self.lock.node.unlocked();
         self.lock.locked.store(false, Release);
     }
 }
@@ -70,3 +80,10 @@ fn main() {
     let g = x.lock();
     assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    // Bounded to 0, like `s1_minimal`: the guard doesn't change how it locks.
+    crate::sync::check_lock_bounded(0, || SpinLock::new(0), |l| *l.lock() += 1, |l| *l.lock());
+}
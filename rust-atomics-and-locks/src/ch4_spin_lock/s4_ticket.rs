@@ -0,0 +1,120 @@
+use std::ops::{Deref, DerefMut};
+use crate::sync::UnsafeCell;
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::cache_padded::CachePadded;
+use crate::sync::hint;
+
+/// A fair spin lock: threads get the lock in the order they started waiting.
+///
+/// Every thread that wants the lock takes a ticket, and waits until
+/// that ticket is being served. Unlike the `AtomicBool::swap` based
+/// lock, a thread can't be overtaken over and over again.
+///
+/// All waiting threads do still spin on the same `now_serving` counter.
+/// See `s5_mcs` for a queue lock where every thread spins on its own cache line.
+pub struct SpinLock<T> {
+    /// The next ticket to hand out.
+    next_ticket: CachePadded<AtomicU32>,
+    /// The ticket of the thread that may hold the lock.
+    /// Only ever modified by the thread holding the lock.
+    now_serving: CachePadded<AtomicU32>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+
+impl<T> SpinLock<T> {
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                next_ticket: CachePadded::new(AtomicU32::new(0)),
+                now_serving: CachePadded::new(AtomicU32::new(0)),
+                value: UnsafeCell::new(value),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> Guard<'_, T> {
+        // Wrapping around is fine, as long as there's
+        // fewer than 2³² threads waiting at once.
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        while self.now_serving.load(Acquire) != ticket {
+            hint::spin_loop();
+        }
+        Guard { lock: self }
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        // We're the only one modifying now_serving,
+        // so this doesn't need to be a read-modify-write operation.
+        let n = self.lock.now_serving.load(Relaxed);
+        self.lock.now_serving.store(n.wrapping_add(1), Release);
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+    let x = SpinLock::new(Vec::new());
+    thread::scope(|s| {
+        s.spawn(|| x.lock().push(1));
+        s.spawn(|| {
+            let mut g = x.lock();
+            g.push(2);
+            g.push(2);
+        });
+    });
+    let g = x.lock();
+    assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
+}
+
+#[test]
+fn fifo() {
+    use std::thread;
+    let x = SpinLock::new(Vec::new());
+    thread::scope(|s| {
+        let g = x.lock();
+        for i in 0..8 {
+            let x = &x;
+            s.spawn(move || x.lock().push(i));
+            // Wait until this thread has taken its ticket before spawning the next one.
+            while x.next_ticket.load(Relaxed) != i + 2 {
+                thread::yield_now();
+            }
+        }
+        drop(g);
+    });
+    assert_eq!(x.lock().as_slice(), [0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::check_lock(|| SpinLock::new(0), |l| *l.lock() += 1, |l| *l.lock());
+}
@@ -0,0 +1,208 @@
+use std::ops::{Deref, DerefMut};
+use crate::sync::UnsafeCell;
+use std::ptr::{self, NonNull};
+use crate::sync::atomic::{AtomicBool, AtomicPtr};
+use crate::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use crate::cache_padded::CachePadded;
+use crate::sync::hint;
+
+/// An MCS queue lock: a fair spin lock where every waiting
+/// thread spins on its own cache line, rather than on a shared one.
+///
+/// Waiting threads form a linked list (queue) of nodes. Every thread only
+/// spins on the `locked` flag of its own node, which is cleared by
+/// its predecessor in the queue when it unlocks.
+pub struct SpinLock<T> {
+    /// The last node in the queue, or null if unlocked.
+    tail: AtomicPtr<Node>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+type Node = CachePadded<NodeData>;
+
+struct NodeData {
+    /// Set to false by our predecessor when it hands the lock over to us.
+    locked: AtomicBool,
+    /// Our successor in the queue, once it has linked itself.
+    next: AtomicPtr<Node>,
+}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+    /// Our node, allocated with a `Box`, since it needs
+    /// a stable address for as long as it's in the queue.
+    node: NonNull<Node>,
+}
+
+unsafe impl<T> Send for Guard<'_, T> where T: Send {}
+unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+
+impl<T> SpinLock<T> {
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                tail: AtomicPtr::new(ptr::null_mut()),
+                value: UnsafeCell::new(value),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> Guard<'_, T> {
+        let node_ptr = Box::into_raw(Box::new(CachePadded::new(NodeData {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        })));
+        // Safety: We only ever create shared references to nodes.
+        let node = unsafe { &*node_ptr };
+        // Release, to publish the node to our successor.
+        // Acquire, to make sure we see our predecessor's node, or,
+        // if there is none, everything from the previous unlock.
+        let prev = self.tail.swap(node_ptr, AcqRel);
+        if !prev.is_null() {
+            // Safety: Our predecessor can't finish unlocking (and drop its
+            // node) before we've linked ourselves through its `next` pointer.
+            unsafe { &*prev }.next.store(node_ptr, Release);
+            while node.locked.load(Acquire) {
+                hint::spin_loop();
+            }
+        }
+        // Safety: Box::into_raw never returns null.
+        Guard { lock: self, node: unsafe { NonNull::new_unchecked(node_ptr) } }
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        let node_ptr = self.node.as_ptr();
+        // Safety: Our node is alive until we free it at the end of this function.
+        let node = unsafe { self.node.as_ref() };
+        let mut next = node.next.load(Acquire);
+        if next.is_null() {
+            // No successor yet. If we're still the tail, the lock is now unlocked.
+            if self.lock.tail.compare_exchange(
+                node_ptr, ptr::null_mut(), Release, Relaxed
+            ).is_ok() {
+                drop(unsafe { Box::from_raw(node_ptr) });
+                return;
+            }
+            // Another thread swapped itself in as the tail, but
+            // didn't link itself to us yet. Wait for it to do so.
+            loop {
+                next = node.next.load(Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                hint::spin_loop();
+            }
+        }
+        // Safety: Our successor keeps spinning on its node until we hand over
+        // the lock here, so it's still alive. This is the last time we touch it.
+        unsafe { &*next }.locked.store(false, Release);
+        // Safety: Nobody else refers to our node anymore.
+        drop(unsafe { Box::from_raw(node_ptr) });
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+    let x = SpinLock::new(Vec::new());
+    thread::scope(|s| {
+        s.spawn(|| x.lock().push(1));
+        s.spawn(|| {
+            let mut g = x.lock();
+            g.push(2);
+            g.push(2);
+        });
+    });
+    let g = x.lock();
+    assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
+}
+
+#[test]
+fn fifo() {
+    use std::thread;
+    let x = SpinLock::new(Vec::new());
+    thread::scope(|s| {
+        let g = x.lock();
+        for i in 0..8 {
+            let x = &x;
+            let tail = x.tail.load(Relaxed);
+            s.spawn(move || x.lock().push(i));
+            // Wait until this thread has queued itself before spawning the next one.
+            while x.tail.load(Relaxed) == tail {
+                thread::yield_now();
+            }
+        }
+        drop(g);
+    });
+    assert_eq!(x.lock().as_slice(), [0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn contention() {
+    use std::thread;
+    let x = SpinLock::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1_000 {
+                    *x.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*x.lock(), 4_000);
+    assert!(x.tail.load(Relaxed).is_null());
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::check_lock(|| SpinLock::new(0), |l| *l.lock() += 1, |l| *l.lock());
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_handoff() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let x = Arc::new(SpinLock::new(0));
+        let mut g = x.lock();
+        let t = thread::spawn({
+            let x = x.clone();
+            move || {
+                let mut g = x.lock();
+                assert_eq!(*g, 1);
+                *g += 1;
+            }
+        });
+        *g += 1;
+        // By now, the other thread might not have queued up yet, it might
+        // have made itself the tail without linking itself to our node yet,
+        // or it might be spinning on its own node, waiting for us.
+        drop(g);
+        t.join().unwrap();
+        assert_eq!(*x.lock(), 2);
+        assert!(x.tail.load(Relaxed).is_null());
+    });
+}
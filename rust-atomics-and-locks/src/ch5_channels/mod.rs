@@ -1,3 +1,4 @@
+pub mod error;
 pub mod s1_simple;
 pub mod s2_unsafe;
 pub mod s3_checks;
@@ -5,3 +6,5 @@ pub mod s3_single_atomic;
 pub mod s4_types;
 pub mod s5_borrowing;
 pub mod s6_blocking;
+pub mod s7_bounded;
+pub mod s8_async;
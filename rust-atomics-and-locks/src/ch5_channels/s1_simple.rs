@@ -1,6 +1,6 @@
 use std::collections::VecDeque;
-use std::sync::Condvar;
-use std::sync::Mutex;
+use crate::sync::Condvar;
+use crate::sync::Mutex;
 
 pub struct Channel<T> {
     queue: Mutex<VecDeque<T>>,
@@ -30,3 +30,22 @@ impl<T> Channel<T> {
         }
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let channel = Arc::new(Channel::new());
+        let t = thread::spawn({
+            let channel = channel.clone();
+            move || {
+                channel.send(1);
+                channel.send(2);
+            }
+        });
+        assert_eq!(channel.receive(), 1);
+        assert_eq!(channel.receive(), 2);
+        t.join().unwrap();
+    });
+}
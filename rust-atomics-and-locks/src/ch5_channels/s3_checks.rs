@@ -1,7 +1,7 @@
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::mem::MaybeUninit;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
@@ -12,11 +12,13 @@ pub struct Channel<T> {
 unsafe impl<T> Sync for Channel<T> where T: Send {}
 
 impl<T> Channel<T> {
-    pub const fn new() -> Self {
-        Self {
-            message: UnsafeCell::new(MaybeUninit::uninit()),
-            in_use: AtomicBool::new(false),
-            ready: AtomicBool::new(false),
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self {
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+                in_use: AtomicBool::new(false),
+                ready: AtomicBool::new(false),
+            }
         }
     }
 
@@ -70,3 +72,21 @@ fn main() {
         assert_eq!(channel.receive(), "hello world!");
     });
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let channel = Arc::new(Channel::new());
+        let t = thread::spawn({
+            let channel = channel.clone();
+            move || channel.send(String::from("hello world!"))
+        });
+        while !channel.is_ready() {
+            thread::yield_now();
+        }
+        assert_eq!(channel.receive(), "hello world!");
+        t.join().unwrap();
+    });
+}
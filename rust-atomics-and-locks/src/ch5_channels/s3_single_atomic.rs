@@ -1,12 +1,12 @@
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::mem::MaybeUninit;
-use std::sync::atomic::AtomicU8;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicU8;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
-const EMPTY: u8 = 0;
-const WRITING: u8 = 1;
-const READY: u8 = 2;
-const READING: u8 = 3;
+pub(super) const EMPTY: u8 = 0;
+pub(super) const WRITING: u8 = 1;
+pub(super) const READY: u8 = 2;
+pub(super) const READING: u8 = 3;
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
@@ -16,10 +16,12 @@ pub struct Channel<T> {
 unsafe impl<T: Send> Sync for Channel<T> {}
 
 impl<T> Channel<T> {
-    pub const fn new() -> Self {
-        Self {
-            message: UnsafeCell::new(MaybeUninit::uninit()),
-            state: AtomicU8::new(EMPTY),
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self {
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+                state: AtomicU8::new(EMPTY),
+            }
         }
     }
 
@@ -71,3 +73,21 @@ fn main() {
         assert_eq!(channel.receive(), "hello world!");
     });
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let channel = Arc::new(Channel::new());
+        let t = thread::spawn({
+            let channel = channel.clone();
+            move || channel.send(String::from("hello world!"))
+        });
+        while !channel.is_ready() {
+            thread::yield_now();
+        }
+        assert_eq!(channel.receive(), "hello world!");
+        t.join().unwrap();
+    });
+}
@@ -1,8 +1,9 @@
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::mem::MaybeUninit;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-use std::sync::Arc;
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::Arc;
+use super::error::{RecvError, SendError, TryRecvError};
 
 pub struct Sender<T> {
     channel: Arc<Channel<T>>,
@@ -16,6 +17,8 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let a = Arc::new(Channel {
         message: UnsafeCell::new(MaybeUninit::uninit()),
         ready: AtomicBool::new(false),
+        sender_dropped: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
     });
     (Sender { channel: a.clone() }, Receiver { channel: a })
 }
@@ -23,12 +26,26 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    /// Set (after `ready`, if anything was sent) when the sender is dropped.
+    sender_dropped: AtomicBool,
+    receiver_dropped: AtomicBool,
 }
 
 impl<T> Sender<T> {
-    pub fn send(self, message: T) {
+    /// Gives the message back if the receiver has been dropped already.
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.receiver_dropped.load(Relaxed) {
+            return Err(SendError(message));
+        }
         unsafe { (*self.channel.message.get()).write(message) };
         self.channel.ready.store(true, Release);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.sender_dropped.store(true, Release);
     }
 }
 
@@ -37,11 +54,33 @@ impl<T> Receiver<T> {
         self.channel.ready.load(Relaxed)
     }
 
-    pub fn receive(self) -> T {
-        if !self.channel.ready.swap(false, Acquire) {
-            panic!("no message available!");
+    /// Panics if there's no message yet, but the sender still exists.
+    pub fn receive(self) -> Result<T, RecvError> {
+        match self.try_receive() {
+            Ok(message) => Ok(message),
+            Err(TryRecvError::Empty) => panic!("no message available!"),
+            Err(TryRecvError::Disconnected) => Err(RecvError::Disconnected),
+        }
+    }
+
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        // Check this first: if the sender is gone, we're
+        // guaranteed to see `ready` if it sent anything.
+        let disconnected = self.channel.sender_dropped.load(Acquire);
+        if self.channel.ready.swap(false, Acquire) {
+            return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+        }
+        if disconnected {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_dropped.store(true, Relaxed);
     }
 }
 
@@ -62,12 +101,42 @@ fn main() {
         let (sender, receiver) = channel();
         let t = thread::current();
         s.spawn(move || {
-            sender.send("hello world!");
+            sender.send("hello world!").unwrap();
             t.unpark();
         });
         while !receiver.is_ready() {
             thread::park();
         }
-        assert_eq!(receiver.receive(), "hello world!");
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+    });
+}
+
+#[test]
+fn disconnect() {
+    let (sender, receiver) = channel::<i32>();
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+    drop(sender);
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Disconnected));
+    assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(String::from("hi")), Err(SendError(String::from("hi"))));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::thread;
+    crate::sync::model(|| {
+        let (sender, receiver) = channel();
+        let t = thread::spawn(move || sender.send(String::from("hello world!")).unwrap());
+        // Only once: under loom, trying again could swap `false` over `false` again instead of
+        // reading the sender's `true`. (See `crate::sync`.) Once disconnected, the message must be there.
+        match receiver.try_receive() {
+            Err(TryRecvError::Empty) => {}
+            r => assert_eq!(r.as_deref(), Ok("hello world!")),
+        }
+        t.join().unwrap();
     });
 }
@@ -1,14 +1,18 @@
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
-use std::thread;
-use std::thread::Thread;
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::thread;
+use crate::sync::thread::Thread;
+use super::error::{RecvError, SendError, TryRecvError};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    /// Set (after `ready`, if anything was sent) when the sender is dropped.
+    sender_dropped: AtomicBool,
+    receiver_dropped: AtomicBool,
 }
 
 unsafe impl<T> Sync for Channel<T> where T: Send {}
@@ -24,10 +28,14 @@ pub struct Receiver<'a, T> {
 }
 
 impl<T> Channel<T> {
-    pub const fn new() -> Self {
-        Self {
-            message: UnsafeCell::new(MaybeUninit::uninit()),
-            ready: AtomicBool::new(false),
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self {
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+                ready: AtomicBool::new(false),
+                sender_dropped: AtomicBool::new(false),
+                receiver_dropped: AtomicBool::new(false),
+            }
         }
     }
 
@@ -47,19 +55,55 @@ impl<T> Channel<T> {
 }
 
 impl<T> Sender<'_, T> {
-    pub fn send(self, message: T) {
+    /// Gives the message back if the receiver has been dropped already.
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.receiver_dropped.load(Relaxed) {
+            return Err(SendError(message));
+        }
         unsafe { (*self.channel.message.get()).write(message) };
         self.channel.ready.store(true, Release);
-        self.receiving_thread.unpark(); // New!
+        // Dropping self unparks the receiving thread.
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.channel.sender_dropped.store(true, Release);
+        // Also when we didn't send anything, so the receiver doesn't park forever.
+        self.receiving_thread.unpark();
     }
 }
 
 impl<T> Receiver<'_, T> {
-    pub fn receive(self) -> T {
-        while !self.channel.ready.swap(false, Acquire) {
-            thread::park();
+    pub fn receive(self) -> Result<T, RecvError> {
+        loop {
+            match self.try_receive() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Empty) => thread::park(),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+            }
+        }
+    }
+
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        // Check this first: if the sender is gone, we're
+        // guaranteed to see `ready` if it sent anything.
+        let disconnected = self.channel.sender_dropped.load(Acquire);
+        if self.channel.ready.swap(false, Acquire) {
+            return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+        if disconnected {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        self.channel.receiver_dropped.store(true, Relaxed);
     }
 }
 
@@ -73,12 +117,57 @@ impl<T> Drop for Channel<T> {
 
 #[test]
 fn main() {
+    use std::thread;
     let mut channel = Channel::new();
     thread::scope(|s| {
         let (sender, receiver) = channel.split();
         s.spawn(move || {
-            sender.send("hello world!");
+            sender.send("hello world!").unwrap();
         });
-        assert_eq!(receiver.receive(), "hello world!");
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+    });
+}
+
+#[test]
+fn disconnect() {
+    use std::thread;
+    let mut channel = Channel::<i32>::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || drop(sender));
+        // Doesn't park forever.
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+    });
+    let (sender, receiver) = channel.split();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(SendError(1)));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::model(|| {
+        // Loom threads can't borrow anything.
+        let channel = Box::leak(Box::new(Channel::new()));
+        let (sender, receiver) = channel.split();
+        // Not joined: loom would take a late unpark for the end of the `join`.
+        // So this might only send once the receiver is gone, and get the message back.
+        thread::spawn(move || _ = sender.send(String::from("hello world!")));
+        // Not `receive`, which tries again after parking, like in `s4_types`.
+        match receiver.try_receive() {
+            Err(TryRecvError::Empty) => {}
+            r => assert_eq!(r.as_deref(), Ok("hello world!")),
+        }
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_disconnect() {
+    crate::sync::model(|| {
+        let channel = Box::leak(Box::new(Channel::<i32>::new()));
+        let (sender, receiver) = channel.split();
+        thread::spawn(move || drop(sender));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
     });
 }
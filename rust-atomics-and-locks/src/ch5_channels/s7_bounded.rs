@@ -0,0 +1,420 @@
+use crate::futex::{wait, wake_all, wake_one};
+use crate::sync::UnsafeCell;
+use std::mem::MaybeUninit;
+use crate::sync::atomic::{fence, AtomicU32, AtomicUsize};
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use crate::sync::Arc;
+use crate::cache_padded::CachePadded;
+use super::error::{RecvError, SendError, TryRecvError, TrySendError};
+
+/// A bounded multi-producer multi-consumer channel,
+/// using Dmitry Vyukov's lock-free ring buffer.
+///
+/// Every slot has a sequence number, telling which lap around the buffer
+/// it's on, and whether it's ready to be written or read. Senders and
+/// receivers claim a position with a compare-and-exchange on `tail` or `head`,
+/// and only then access that slot.
+///
+/// Sending to a full channel and receiving from an empty one blocks,
+/// using a futex, but that costs nothing when nobody is blocked.
+///
+/// Once all receivers are gone, sending fails. Once all senders are gone,
+/// receiving fails, but only after all messages have been received.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be non-zero");
+    let a = Arc::new(Channel {
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+        slots: (0..capacity).map(|i| Slot {
+            sequence: AtomicUsize::new(i * 2),
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+        }).collect(),
+        senders: Waiters::new(),
+        receivers: Waiters::new(),
+        sender_count: AtomicUsize::new(1),
+        receiver_count: AtomicUsize::new(1),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+struct Channel<T> {
+    /// The position of the next message to receive.
+    head: CachePadded<AtomicUsize>,
+    /// The position of the next message to send.
+    tail: CachePadded<AtomicUsize>,
+    slots: Box<[Slot<T>]>,
+    /// Senders waiting for the channel to no longer be full.
+    senders: Waiters,
+    /// Receivers waiting for the channel to no longer be empty.
+    receivers: Waiters,
+    /// The number of `Sender`s. Once it's zero, it stays zero.
+    sender_count: AtomicUsize,
+    /// The number of `Receiver`s. Once it's zero, it stays zero.
+    receiver_count: AtomicUsize,
+}
+
+struct Slot<T> {
+    /// Twice the position if the slot is ready to be written at that position,
+    /// or twice the position plus one if it's ready to be read at that position.
+    /// (Not just the position, since with a single slot, being ready to be read at
+    /// one position would look the same as being ready to be written at the next.)
+    sequence: AtomicUsize,
+    message: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Waiters {
+    /// Incremented whenever a waiting thread should check again.
+    counter: CachePadded<AtomicU32>,
+    /// The number of threads that are (about to start) waiting.
+    waiting: AtomicUsize,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.sender_count.fetch_add(1, Relaxed);
+        Self { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receiver_count.fetch_add(1, Relaxed);
+        Self { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Release, so a receiver that sees zero also sees every message that was sent.
+        if self.channel.sender_count.fetch_sub(1, Release) == 1 {
+            self.channel.receivers.wake_all();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receiver_count.fetch_sub(1, Relaxed) == 1 {
+            self.channel.senders.wake_all();
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Blocks while the channel is full.
+    /// Gives the message back if all receivers have been dropped.
+    pub fn send(&self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(m)) => message = m,
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+            }
+            let waiters = &self.channel.senders;
+            waiters.waiting.fetch_add(1, Relaxed);
+            // Pairs with the fence in Waiters::wake: either that receiver sees we're waiting,
+            // or we see the slot it freed up (or that it was the last one) when trying again below.
+            fence(SeqCst);
+            let counter = waiters.counter.load(Acquire);
+            let r = self.try_send(message);
+            if let Err(TrySendError::Full(_)) = r {
+                wait(&waiters.counter, counter);
+            }
+            waiters.waiting.fetch_sub(1, Relaxed);
+            match r {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(m)) => message = m,
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+            }
+        }
+    }
+
+    /// Gives the message back if the channel is full, or all receivers have been dropped.
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let c = &*self.channel;
+        if c.receiver_count.load(Relaxed) == 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        let mut pos = c.tail.load(Relaxed);
+        loop {
+            let slot = &c.slots[pos % c.slots.len()];
+            let seq = slot.sequence.load(Acquire);
+            match seq.wrapping_sub(pos.wrapping_mul(2)) as isize {
+                0 => match c.tail.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), Relaxed, Relaxed
+                ) {
+                    Ok(_) => {
+                        // Safety: We've claimed this slot for this position,
+                        // and its previous message has been received.
+                        unsafe { (*slot.message.get()).write(message) };
+                        slot.sequence.store(pos.wrapping_mul(2).wrapping_add(1), Release);
+                        c.receivers.wake_one();
+                        return Ok(());
+                    }
+                    Err(p) => pos = p,
+                },
+                // The slot still holds a message from the previous lap: full.
+                d if d < 0 => return Err(TrySendError::Full(message)),
+                // Another sender claimed this position already.
+                _ => pos = c.tail.load(Relaxed),
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Blocks while the channel is empty.
+    /// Fails if it's empty and all senders have been dropped.
+    pub fn receive(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_receive() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+            }
+            let waiters = &self.channel.receivers;
+            waiters.waiting.fetch_add(1, Relaxed);
+            // Pairs with the fence in Waiters::wake: either that sender sees we're waiting,
+            // or we see its message (or that it was the last one) when trying again below.
+            fence(SeqCst);
+            let counter = waiters.counter.load(Acquire);
+            let r = self.try_receive();
+            if let Err(TryRecvError::Empty) = r {
+                wait(&waiters.counter, counter);
+            }
+            waiters.waiting.fetch_sub(1, Relaxed);
+            match r {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+            }
+        }
+    }
+
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        let c = &*self.channel;
+        // Check this first: if the senders are gone, we're
+        // guaranteed to see every message they sent.
+        let disconnected = c.sender_count.load(Acquire) == 0;
+        let mut pos = c.head.load(Relaxed);
+        loop {
+            let slot = &c.slots[pos % c.slots.len()];
+            let seq = slot.sequence.load(Acquire);
+            match seq.wrapping_sub(pos.wrapping_mul(2).wrapping_add(1)) as isize {
+                0 => match c.head.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), Relaxed, Relaxed
+                ) {
+                    Ok(_) => {
+                        // Safety: We've claimed this slot for this position,
+                        // and its message has been fully written.
+                        let message = unsafe { (*slot.message.get()).assume_init_read() };
+                        // Ready to be written in the next lap.
+                        slot.sequence.store(pos.wrapping_add(c.slots.len()).wrapping_mul(2), Release);
+                        c.senders.wake_one();
+                        return Ok(message);
+                    }
+                    Err(p) => pos = p,
+                },
+                // The slot hasn't been written in this lap yet: empty.
+                d if d < 0 => {
+                    return Err(if disconnected { TryRecvError::Disconnected } else { TryRecvError::Empty });
+                }
+                // Another receiver claimed this position already.
+                _ => pos = c.head.load(Relaxed),
+            }
+        }
+    }
+}
+
+impl Waiters {
+    fn new() -> Self {
+        Self {
+            counter: CachePadded::new(AtomicU32::new(0)),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Called after making progress, to wake up a thread waiting for that, if any.
+    fn wake_one(&self) {
+        self.wake(wake_one);
+    }
+
+    /// Called once the other side is gone, to wake up all threads waiting for anything.
+    fn wake_all(&self) {
+        self.wake(wake_all);
+    }
+
+    fn wake(&self, wake: fn(&AtomicU32)) {
+        fence(SeqCst);
+        if self.waiting.load(Relaxed) > 0 {
+            // Release, so a waiter that sees this increment also sees our progress.
+            self.counter.fetch_add(1, Release);
+            wake(&self.counter);
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        let mut pos = head;
+        while pos != tail {
+            let slot = &mut self.slots[pos % self.slots.len()];
+            // Safety: Everything between head and tail has been sent, but not received.
+            unsafe { slot.message.get_mut().assume_init_drop() };
+            pos = pos.wrapping_add(1);
+        }
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+    let (sender, receiver) = channel(4);
+    let total = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for t in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..10_000 {
+                    sender.send(t * 10_000 + i).unwrap();
+                }
+            });
+        }
+        for _ in 0..4 {
+            let receiver = receiver.clone();
+            let total = &total;
+            s.spawn(move || {
+                for _ in 0..10_000 {
+                    total.fetch_add(receiver.receive().unwrap(), Relaxed);
+                }
+            });
+        }
+    });
+    assert_eq!(total.into_inner(), (0..40_000).sum());
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn full() {
+    let (sender, receiver) = channel(2);
+    sender.send(String::from("a")).unwrap();
+    assert_eq!(sender.try_send(String::from("b")), Ok(()));
+    assert_eq!(sender.try_send(String::from("c")), Err(TrySendError::Full(String::from("c"))));
+    assert_eq!(receiver.receive().unwrap(), "a");
+    sender.send(String::from("c")).unwrap();
+    assert_eq!(receiver.try_receive().as_deref(), Ok("b"));
+    // "c" is dropped together with the channel.
+}
+
+#[test]
+fn capacity_one() {
+    let (sender, receiver) = channel(1);
+    sender.send(1).unwrap();
+    // Not the slot for the next position yet: it still holds 1.
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.receive(), Ok(1));
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+    sender.send(3).unwrap();
+    assert_eq!(receiver.receive(), Ok(3));
+}
+
+#[test]
+fn senders_dropped() {
+    use std::thread;
+    let (sender, receiver) = channel(2);
+    let sender2 = sender.clone();
+    sender.send(1).unwrap();
+    drop(sender);
+    // Messages that were sent can still be received.
+    assert_eq!(receiver.receive(), Ok(1));
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+    thread::scope(|s| {
+        s.spawn(|| {
+            // Wakes up once the last sender is gone.
+            assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+        });
+        thread::sleep(std::time::Duration::from_millis(10));
+        drop(sender2);
+    });
+    assert_eq!(receiver.try_receive(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn receivers_dropped() {
+    use std::thread;
+    let (sender, receiver) = channel(1);
+    let receiver2 = receiver.clone();
+    drop(receiver);
+    sender.send(String::from("a")).unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            // Wakes up once the last receiver is gone.
+            assert_eq!(sender.send(String::from("b")), Err(SendError(String::from("b"))));
+        });
+        thread::sleep(std::time::Duration::from_millis(10));
+        drop(receiver2);
+    });
+    assert_eq!(sender.try_send(String::from("c")), Err(TrySendError::Disconnected(String::from("c"))));
+    // "a" is dropped together with the channel.
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::thread;
+    // Every interleaving takes minutes. A lost wakeup needs two preemptions: one
+    // between checking whether to sleep and sleeping, and one to switch back after
+    // the other side made that check wrong. Four allow that on both sides.
+    crate::sync::model_bounded(4, || {
+        // Both sides have to block: the sender on the second
+        // message, and the receiver whenever it gets ahead.
+        let (sender, receiver) = channel(1);
+        let t = thread::spawn(move || {
+            sender.send(1).unwrap();
+            sender.send(2).unwrap();
+        });
+        assert_eq!(receiver.receive(), Ok(1));
+        assert_eq!(receiver.receive(), Ok(2));
+        t.join().unwrap();
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_senders_dropped() {
+    use crate::sync::thread;
+    // Bounded like `loom`, for the same reason.
+    crate::sync::model_bounded(4, || {
+        // Woken up by the message or by the sender's drop, and must see the message.
+        let (sender, receiver) = channel(1);
+        let t = thread::spawn(move || sender.send(1).unwrap());
+        assert_eq!(receiver.receive(), Ok(1));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+        t.join().unwrap();
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_receivers_dropped() {
+    use crate::sync::thread;
+    crate::sync::model(|| {
+        let (sender, receiver) = channel(1);
+        sender.send(1).unwrap();
+        let t = thread::spawn(move || drop(receiver));
+        assert_eq!(sender.send(2), Err(SendError(2)));
+        t.join().unwrap();
+    });
+}
@@ -0,0 +1,287 @@
+use crate::sync::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use crate::sync::atomic::AtomicU8;
+use crate::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use crate::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use crate::sync::thread::{self, Thread};
+use super::error::{RecvError, SendError};
+use super::s3_single_atomic::{EMPTY, READING, READY, WRITING};
+
+/// A oneshot channel whose `Receiver` is a `Future`,
+/// using the same state machine as `s3_single_atomic`.
+///
+/// Instead of unparking a thread, the sender wakes up the task
+/// that last polled the receiver. `Receiver::receive` still works
+/// from blocking code, by polling with a waker that unparks the thread.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        state: AtomicU8::new(EMPTY),
+        waker: AtomicWaker::new(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+/// The other side was dropped before anything was sent.
+const CLOSED: u8 = 4;
+
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+    waker: AtomicWaker,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Sender<T> {
+    /// Gives the message back if the receiver has been dropped already.
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.state.compare_exchange(
+            EMPTY, WRITING, Relaxed, Relaxed
+        ).is_err() {
+            return Err(SendError(message));
+        }
+        unsafe { (*self.channel.message.get()).write(message) };
+        self.channel.state.store(READY, Release);
+        self.channel.waker.wake();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Nothing to do if we sent something (or the receiver is gone).
+        if self.channel.state.compare_exchange(
+            EMPTY, CLOSED, Relaxed, Relaxed
+        ).is_ok() {
+            self.channel.waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn is_ready(&self) -> bool {
+        self.channel.state.load(Relaxed) == READY
+    }
+
+    /// Blocks the current thread until the message arrives.
+    pub fn receive(mut self) -> Result<T, RecvError> {
+        let waker = Waker::from(std::sync::Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(r) = Pin::new(&mut self).poll(&mut cx) {
+                return r;
+            }
+            thread::park();
+        }
+    }
+
+    fn try_take(&self) -> Option<Result<T, RecvError>> {
+        match self.channel.state.compare_exchange(READY, READING, Acquire, Relaxed) {
+            Ok(_) => Some(Ok(unsafe { (*self.channel.message.get()).assume_init_read() })),
+            Err(CLOSED) => Some(Err(RecvError::Disconnected)),
+            Err(READING) => panic!("receiver polled after completion!"),
+            Err(_) => None,
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(r) = self.try_take() {
+            return Poll::Ready(r);
+        }
+        self.channel.waker.register(cx.waker());
+        // Check again: the sender might have finished right
+        // before we registered, without seeing our waker.
+        match self.try_take() {
+            Some(r) => Poll::Ready(r),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // If a message was sent (or is being written) anyway,
+        // dropping the channel will drop it.
+        let _ = self.channel.state.compare_exchange(EMPTY, CLOSED, Relaxed, Relaxed);
+        // Don't keep a cancelled task's waker around.
+        self.channel.waker.take();
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: std::sync::Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &std::sync::Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// A slot for a `Waker`, that can be registered and woken at the same time,
+/// the same way as `futures::task::AtomicWaker`.
+///
+/// Only one thread may call `register` at a time.
+struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+const WAITING: u8 = 0;
+const REGISTERING: u8 = 0b01;
+const WAKING: u8 = 0b10;
+
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    crate::sync::const_fn! {
+        const fn new() -> Self {
+            Self { state: AtomicU8::new(WAITING), waker: UnsafeCell::new(None) }
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(WAITING, REGISTERING, Acquire, Acquire) {
+            Ok(_) => {
+                // Safety: The REGISTERING bit gives us exclusive access to the slot.
+                let old = unsafe { (*self.waker.get()).replace(waker.clone()) };
+                if let Err(state) = self.state.compare_exchange(
+                    REGISTERING, WAITING, AcqRel, Acquire
+                ) {
+                    // A wake() came in while we were registering,
+                    // and left it up to us to do the waking.
+                    debug_assert_eq!(state, REGISTERING | WAKING);
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+                drop(old);
+            }
+            Err(WAKING) => {
+                // We're being woken right now; make sure we get polled again.
+                waker.wake_by_ref();
+            }
+            Err(_) => panic!("concurrent calls to AtomicWaker::register!"),
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, AcqRel) {
+            WAITING => {
+                // Safety: The WAKING bit gives us exclusive access to the slot.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Release);
+                waker
+            }
+            // Either a register() is in progress, which will see the WAKING
+            // bit and wake the task itself, or another thread is waking it.
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+    thread::scope(|s| {
+        let (sender, receiver) = channel();
+        s.spawn(move || {
+            sender.send("hello world!").unwrap();
+        });
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+    });
+}
+
+#[test]
+fn poll() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    let count = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let (sender, mut receiver) = channel();
+    assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+    assert_eq!(count.0.load(Relaxed), 0);
+    sender.send(123).unwrap();
+    assert_eq!(count.0.load(Relaxed), 1);
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Ok(123)));
+
+    // Dropping the sender wakes the receiver too.
+    let (sender, mut receiver) = channel::<i32>();
+    assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+    drop(sender);
+    assert_eq!(count.0.load(Relaxed), 2);
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Err(RecvError::Disconnected)));
+
+    // Cancelling the receiver halfway through.
+    let (sender, mut receiver) = channel();
+    assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+    drop(receiver);
+    assert_eq!(sender.send(String::from("hi")), Err(SendError(String::from("hi"))));
+    assert_eq!(count.0.load(Relaxed), 2);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::model(|| {
+        let (sender, receiver) = channel();
+        // Not joined: loom would take a late unpark for the end of the `join`.
+        thread::spawn(move || sender.send(String::from("hello world!")).unwrap());
+        assert_eq!(receiver.receive().as_deref(), Ok("hello world!"));
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_disconnect() {
+    crate::sync::model(|| {
+        let (sender, receiver) = channel::<i32>();
+        thread::spawn(move || drop(sender));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+    });
+}
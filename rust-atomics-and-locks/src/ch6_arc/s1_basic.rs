@@ -1,7 +1,7 @@
 use std::ops::Deref;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::fence;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::atomic::fence;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::ptr::NonNull;
 
 struct ArcData<T> {
@@ -74,6 +74,8 @@ impl<T> Drop for Arc<T> {
 
 #[test]
 fn test() {
+    use std::sync::atomic::AtomicUsize;
+
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
 
     struct DetectDrop;
@@ -111,3 +113,18 @@ fn test() {
     // the object should've been dropped.
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Tracked};
+    crate::sync::model(|| {
+        let x = Arc::new(Tracked::new(1));
+        let y = x.clone();
+        let t = thread::spawn(move || assert_eq!(y.get(), 1));
+        assert_eq!(x.get(), 1);
+        // Whichever thread drops it last, it happens after the other one is done with it.
+        drop(x);
+        t.join().unwrap();
+    });
+}
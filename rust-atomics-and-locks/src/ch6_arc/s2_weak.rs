@@ -1,8 +1,8 @@
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::ops::Deref;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::fence;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::atomic::fence;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::ptr::NonNull;
 
 struct ArcData<T> {
@@ -89,7 +89,7 @@ impl<T> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        let ptr = self.weak.data().data.get();
+        let ptr = self.weak.data().data.get_const();
         // Safety: Since there's an Arc to the data,
         // the data exists and may be shared.
         unsafe { (*ptr).as_ref().unwrap() }
@@ -142,6 +142,8 @@ impl<T> Drop for Arc<T> {
 
 #[test]
 fn test() {
+    use std::sync::atomic::AtomicUsize;
+
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
 
     struct DetectDrop;
@@ -177,3 +179,20 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Tracked};
+    crate::sync::model(|| {
+        let x = Arc::new(Tracked::new(1));
+        let w = Arc::downgrade(&x);
+        let t = thread::spawn(move || {
+            if let Some(x) = w.upgrade() {
+                assert_eq!(x.get(), 1);
+            }
+        });
+        drop(x);
+        t.join().unwrap();
+    });
+}
@@ -1,26 +1,35 @@
-use std::mem::ManuallyDrop;
-use std::cell::UnsafeCell;
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use crate::sync::UnsafeCell;
 use std::ops::Deref;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::fence;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-use std::ptr::NonNull;
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::atomic::fence;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::ptr::{self, NonNull};
+use crate::sync::hint;
 
-pub struct Arc<T> {
+/// Unlike `std::sync::Arc`, this can't be unsize-coerced (e.g. from `Arc<[T; N]>`
+/// to `Arc<[T]>`) on stable Rust. Instead, an `Arc<[T]>`, `Arc<str>` or
+/// `Arc<dyn Trait>` can be created using `From`, e.g. from a `Box<dyn Trait>`.
+pub struct Arc<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Sync + Send> Send for Arc<T> {}
-unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Arc<T> {}
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized> {
+    /// Dangling (usize::MAX) for a `Weak::new()`, which has no allocation.
     ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Weak<T> {}
 
-struct ArcData<T> {
+// repr(C), so we know where `data` is, to allocate it ourselves for unsized T,
+// and to get from a pointer to the data back to the ArcData in `from_raw`.
+#[repr(C)]
+struct ArcData<T: ?Sized> {
     /// Number of `Arc`s.
     data_ref_count: AtomicUsize,
     /// Number of `Weak`s, plus one if there are any `Arc`s.
@@ -40,6 +49,96 @@ impl<T> Arc<T> {
         }
     }
 
+    /// Creates an `Arc` to data that contains a `Weak` to itself.
+    ///
+    /// Upgrading that `Weak` fails until this function returns.
+    pub fn new_cyclic(f: impl FnOnce(&Weak<T>) -> T) -> Arc<T> {
+        // Start out with no `Arc`s and one `Weak`.
+        let uninit = NonNull::from(Box::leak(Box::new(ArcData {
+            alloc_ref_count: AtomicUsize::new(1),
+            data_ref_count: AtomicUsize::new(0),
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+        })));
+        // Safety: MaybeUninit<T> has the same layout as T, and as long as
+        // there are no `Arc`s, nothing will touch the data.
+        let weak = Weak { ptr: uninit.cast::<ArcData<T>>() };
+        // If this panics, dropping `weak` deallocates everything again.
+        let data = f(&weak);
+        // Safety: Nothing else accesses the data while there are no `Arc`s.
+        unsafe { (*uninit.as_ref().data.get()).write(data) };
+        // Release, to match Weak::upgrade's Acquire, for the data we just wrote.
+        weak.data().data_ref_count.store(1, Release);
+        // Our `Weak` turns into the implicit one that represents all `Arc`s.
+        let weak = ManuallyDrop::new(weak);
+        Arc { ptr: weak.ptr }
+    }
+
+    /// Returns the data if this is the only `Arc`, even if there are `Weak`s.
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        if arc.data().data_ref_count.compare_exchange(1, 0, Relaxed, Relaxed).is_err() {
+            return Err(arc);
+        }
+        // Acquire to match Arc::drop's Release decrement.
+        fence(Acquire);
+        Ok(Self::take_data(arc))
+    }
+
+    /// Drops this `Arc`, but returns the data if it was the last one.
+    ///
+    /// Unlike `try_unwrap(arc).ok()`, this can't fail for all threads
+    /// that drop their `Arc` at the same time.
+    pub fn into_inner(arc: Self) -> Option<T> {
+        let arc = ManuallyDrop::new(arc);
+        if arc.data().data_ref_count.fetch_sub(1, Release) != 1 {
+            return None;
+        }
+        fence(Acquire);
+        Some(Self::take_data(ManuallyDrop::into_inner(arc)))
+    }
+
+    /// Gives mutable access to the data, cloning it first if it's shared.
+    ///
+    /// If there are only `Weak`s left besides this `Arc`, the data is moved to
+    /// a new allocation instead, and those `Weak`s can no longer be upgraded.
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // Temporarily set the count to zero, which stops any `Weak` from being
+        // upgraded, while we check if there are any.
+        // Acquire to match Arc::drop's Release decrement, in case we're the last one.
+        if arc.data().data_ref_count.compare_exchange(1, 0, Acquire, Relaxed).is_err() {
+            // There are other `Arc`s.
+            *arc = Arc::new(T::clone(arc));
+        } else if arc.data().alloc_ref_count.load(Relaxed) != 1 {
+            // There are `Weak`s, but no other `Arc`s. Move the data out,
+            // leaving the old allocation behind for the `Weak`s.
+            // Safety: The data_ref_count is zero, so we're the only one accessing the data.
+            let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            let old = mem::replace(arc, Arc::new(data));
+            drop(Weak { ptr: ManuallyDrop::new(old).ptr });
+        } else {
+            // We were the only `Arc` or `Weak`. Nobody could have
+            // observed the zero, so just put the 1 back.
+            arc.data().data_ref_count.store(1, Release);
+        }
+        // Safety: We've made sure this is the only `Arc`, without `Weak`s.
+        unsafe { &mut *arc.data().data.get() }
+    }
+
+    /// Moves the data out, after the data_ref_count has reached zero.
+    fn take_data(arc: Self) -> T {
+        let arc = ManuallyDrop::new(arc);
+        // Safety: There are no other `Arc`s left, and nothing will
+        // access (or drop) the data anymore.
+        let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+        // Drop the implicit weak pointer that represented all `Arc<T>`s.
+        drop(Weak { ptr: arc.ptr });
+        data
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -70,7 +169,7 @@ impl<T> Arc<T> {
         let mut n = arc.data().alloc_ref_count.load(Relaxed);
         loop {
             if n == usize::MAX {
-                std::hint::spin_loop();
+                hint::spin_loop();
                 n = arc.data().alloc_ref_count.load(Relaxed);
                 continue;
             }
@@ -87,34 +186,175 @@ impl<T> Arc<T> {
             return Weak { ptr: arc.ptr };
         }
     }
+
+    /// Returns true if both point to the same allocation.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        // Only compare addresses, not the vtables of `dyn Trait`s.
+        a.ptr.as_ptr() as *const () == b.ptr.as_ptr() as *const ()
+    }
+
+    /// The number of `Arc`s. Might have changed by the time this returns.
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data().data_ref_count.load(Relaxed)
+    }
+
+    /// The number of `Weak`s. Might have changed by the time this returns.
+    pub fn weak_count(arc: &Self) -> usize {
+        match arc.data().alloc_ref_count.load(Relaxed) {
+            // Temporarily locked by get_mut, which only succeeds if there are no `Weak`s.
+            usize::MAX => 0,
+            n => n - 1,
+        }
+    }
+
+    /// Turns this `Arc` into a pointer to the data, without dropping it.
+    /// The only way to ever drop it is to turn it back using `from_raw`.
+    pub fn into_raw(arc: Self) -> *const T {
+        let arc = ManuallyDrop::new(arc);
+        // Not through `deref`, to keep the provenance of the whole allocation.
+        unsafe { UnsafeCell::raw_get(ptr::addr_of!((*arc.ptr.as_ptr()).data)) as *const T }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `Arc::into_raw`,
+    /// and can only be turned back into an `Arc` once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // Safety: The data is still alive, since the `Arc` we came from was never dropped.
+        let offset = data_offset(mem::align_of_val(&*ptr));
+        let arc_data = set_data_ptr(ptr as *mut T, (ptr as *mut u8).sub(offset));
+        Arc { ptr: NonNull::new_unchecked(arc_data as *mut ArcData<T>) }
+    }
+}
+
+/// The offset of `ArcData::data`, for a `T` with the given alignment.
+fn data_offset(align: usize) -> usize {
+    let header = Layout::new::<ArcData<()>>().size();
+    (header + align - 1) & !(align - 1)
+}
+
+/// Replaces the address of a (possibly wide) pointer with `data`, keeping the rest
+/// (the length of a slice, or the vtable of a `dyn Trait`) as is.
+///
+/// The result must get its provenance from `data`, not `ptr`: for `From<Box>`, `data`
+/// is in another allocation. Offsetting `ptr` would keep `ptr`'s provenance, and there's
+/// no stable way to combine `data` with `ptr`'s metadata. So this writes `data` over the
+/// address in `ptr`, which is the first word of a wide pointer in practice.
+unsafe fn set_data_ptr<T: ?Sized>(mut ptr: *mut T, data: *mut u8) -> *mut T {
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, data);
+    debug_assert_eq!(ptr as *mut u8, data, "the address isn't the first word of a wide pointer");
+    ptr
+}
+
+impl<T: ?Sized> From<Box<T>> for Arc<T> {
+    fn from(b: Box<T>) -> Arc<T> {
+        let value_layout = Layout::for_value(&*b);
+        let (layout, offset) = Layout::new::<ArcData<()>>().extend(value_layout).unwrap();
+        let layout = layout.pad_to_align();
+        debug_assert_eq!(offset, data_offset(value_layout.align()));
+        unsafe {
+            let mem = alloc(layout);
+            if mem.is_null() {
+                handle_alloc_error(layout);
+            }
+            let b = Box::into_raw(b);
+            // Safety: Since `ArcData` is repr(C), the data goes at `offset`,
+            // and this pointer's metadata is that of the data.
+            let arc_data = set_data_ptr(b, mem) as *mut ArcData<T>;
+            // Everything before the data: the counters, and whatever else an
+            // `UnsafeCell` might have in front of its value (such as under loom).
+            (mem as *mut ArcData<()>).write(ArcData {
+                data_ref_count: AtomicUsize::new(1),
+                alloc_ref_count: AtomicUsize::new(1),
+                data: UnsafeCell::new(ManuallyDrop::new(())),
+            });
+            // Move the data over, and free the box without dropping it.
+            ptr::copy_nonoverlapping(b as *const u8, mem.add(offset), value_layout.size());
+            if value_layout.size() != 0 {
+                dealloc(b as *mut u8, value_layout);
+            }
+            // Layout::for_value of this ArcData is `layout`, so it can be freed like a Box.
+            Arc { ptr: NonNull::new_unchecked(arc_data) }
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(v: Vec<T>) -> Arc<[T]> {
+        Arc::from(v.into_boxed_slice())
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(s: &[T]) -> Arc<[T]> {
+        Arc::from(Box::<[T]>::from(s))
+    }
+}
+
+impl From<String> for Arc<str> {
+    fn from(s: String) -> Arc<str> {
+        Arc::from(s.into_boxed_str())
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(s: &str) -> Arc<str> {
+        Arc::from(Box::<str>::from(s))
+    }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
         // Safety: Since there's an Arc to the data,
         // the data exists and may be shared.
-        unsafe { &*self.data().data.get() }
+        unsafe { &*self.data().data.get_const() }
     }
 }
 
 impl<T> Weak<T> {
+    /// A `Weak` that never upgrades, without allocating anything.
+    pub const fn new() -> Self {
+        // Safety: usize::MAX is not null.
+        Weak { ptr: unsafe { NonNull::new_unchecked(usize::MAX as *mut ArcData<T>) } }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    /// None for a `Weak::new()`.
+    fn try_data(&self) -> Option<&ArcData<T>> {
+        if self.ptr.as_ptr() as *mut () as usize == usize::MAX {
+            None
+        } else {
+            Some(unsafe { self.ptr.as_ref() })
+        }
+    }
+
     fn data(&self) -> &ArcData<T> {
-        unsafe { self.ptr.as_ref() }
+        self.try_data().expect("dangling Weak")
     }
 
     pub fn upgrade(&self) -> Option<Arc<T>> {
-        let mut n = self.data().data_ref_count.load(Relaxed);
+        let data = self.try_data()?;
+        let mut n = data.data_ref_count.load(Relaxed);
         loop {
             if n == 0 {
                 return None;
             }
             assert!(n <= usize::MAX / 2);
+            // Acquire synchronises with new_cyclic's release-store, for when we
+            // got this `Weak` before the data was there. (Otherwise, whatever
+            // gave us this `Weak` already made sure we can see the data.)
             if let Err(e) =
-                self.data()
-                    .data_ref_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+                data.data_ref_count
+                    .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 n = e;
                 continue;
@@ -124,18 +364,21 @@ impl<T> Weak<T> {
     }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized> Clone for Weak<T> {
     fn clone(&self) -> Self {
-        if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
-            std::process::abort();
+        if let Some(data) = self.try_data() {
+            if data.alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+                std::process::abort();
+            }
         }
         Weak { ptr: self.ptr }
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
+        let Some(data) = self.try_data() else { return };
+        if data.alloc_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
             unsafe {
                 drop(Box::from_raw(self.ptr.as_ptr()));
@@ -144,7 +387,7 @@ impl<T> Drop for Weak<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized> Clone for Arc<T> {
     fn clone(&self) -> Self {
         if self.data().data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             std::process::abort();
@@ -153,7 +396,7 @@ impl<T> Clone for Arc<T> {
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
         if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
@@ -171,6 +414,8 @@ impl<T> Drop for Arc<T> {
 
 #[test]
 fn test() {
+    use std::sync::atomic::AtomicUsize;
+
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
 
     struct DetectDrop;
@@ -206,3 +451,123 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[test]
+fn unsized_types() {
+    let a: Arc<[i32]> = Arc::from(vec![1, 2, 3]);
+    let b = a.clone();
+    assert_eq!(&*b, [1, 2, 3]);
+    assert!(Arc::ptr_eq(&a, &b));
+
+    let s: Arc<str> = Arc::from("hello");
+    let w = Arc::downgrade(&s);
+    assert_eq!(&*w.upgrade().unwrap(), "hello");
+    drop(s);
+    assert!(w.upgrade().is_none());
+
+    let f: Arc<dyn Fn() -> u64 + Send + Sync> = Arc::from(Box::new(|| 42u64) as Box<dyn Fn() -> u64 + Send + Sync>);
+    std::thread::spawn(move || assert_eq!(f(), 42)).join().unwrap();
+
+    // Over-aligned data.
+    #[repr(align(64))]
+    struct Aligned(u8);
+    let a: Arc<[Aligned]> = Arc::from(vec![Aligned(1), Aligned(2)]);
+    assert_eq!(&a[1] as *const Aligned as usize % 64, 0);
+    let p = Arc::into_raw(a);
+    let a = unsafe { Arc::from_raw(p) };
+    assert_eq!(a[1].0, 2);
+
+    let p = Arc::into_raw(Arc::<str>::from(String::from("raw")));
+    assert_eq!(unsafe { &*p }, "raw");
+    drop(unsafe { Arc::from_raw(p) });
+}
+
+#[test]
+fn api() {
+    let mut a = Arc::new(vec![1]);
+    let b = a.clone();
+    assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (2, 0));
+
+    // Shared with another Arc: clones.
+    Arc::make_mut(&mut a).push(2);
+    assert_eq!(*a, [1, 2]);
+    assert_eq!(*b, [1]);
+    assert!(!Arc::ptr_eq(&a, &b));
+
+    // Only shared with a Weak: moves, and disassociates the Weak.
+    let w = Arc::downgrade(&a);
+    assert_eq!(Arc::weak_count(&a), 1);
+    Arc::make_mut(&mut a).push(3);
+    assert!(w.upgrade().is_none());
+    assert_eq!(*a, [1, 2, 3]);
+
+    // Unique: no copy.
+    let p = a.as_ptr();
+    Arc::make_mut(&mut a).push(4);
+    assert_eq!(a.as_ptr(), p);
+
+    let b2 = b.clone();
+    let b = Arc::try_unwrap(b).unwrap_err();
+    drop(b2);
+    assert_eq!(Arc::try_unwrap(b).ok(), Some(vec![1]));
+
+    let c = Arc::new(String::from("x"));
+    let d = c.clone();
+    assert_eq!(Arc::into_inner(c), None);
+    assert_eq!(Arc::into_inner(d).as_deref(), Some("x"));
+
+    let w = Weak::<i32>::new();
+    assert!(w.upgrade().is_none());
+    drop(w.clone());
+
+    struct Node {
+        me: Weak<Node>,
+    }
+    let n = Arc::new_cyclic(|me| {
+        assert!(me.upgrade().is_none());
+        Node { me: me.clone() }
+    });
+    assert!(Arc::ptr_eq(&n.me.upgrade().unwrap(), &n));
+    assert_eq!(Arc::weak_count(&n), 1);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_upgrade() {
+    use crate::sync::{thread, Tracked};
+    crate::sync::model(|| {
+        let x = Arc::new(Tracked::new(1));
+        let w = Arc::downgrade(&x);
+        let t = thread::spawn(move || {
+            if let Some(x) = w.upgrade() {
+                assert_eq!(x.get(), 1);
+            }
+        });
+        // Races with the upgrade: either that fails, or this isn't the last `Arc`.
+        drop(x);
+        t.join().unwrap();
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_get_mut() {
+    use crate::sync::{thread, Tracked};
+    crate::sync::model(|| {
+        let mut x = Arc::new(Tracked::new(1));
+        let y = x.clone();
+        let t = thread::spawn(move || {
+            // Turns the only other `Arc` into a `Weak` and back, which
+            // `get_mut` must not miss, even though it only checks one counter at a time.
+            let w = Arc::downgrade(&y);
+            drop(y);
+            if let Some(y) = w.upgrade() {
+                assert_eq!(y.get(), 1);
+            }
+        });
+        if let Some(x) = Arc::get_mut(&mut x) {
+            x.set(2);
+        }
+        t.join().unwrap();
+    });
+}
@@ -0,0 +1,211 @@
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use crate::sync::atomic::{fence, AtomicPtr, AtomicUsize};
+use crate::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
+use crate::sync::Mutex;
+use crate::sync::thread;
+use super::s3_optimized::Arc;
+
+/// An `Arc<T>` that can be loaded and replaced atomically.
+///
+/// Loading is lock-free, but cloning the `Arc` isn't a single atomic operation:
+/// between reading the pointer and incrementing its reference counter, a writer
+/// could replace it and drop the last `Arc`, freeing the `ArcData` we're about to touch.
+///
+/// To prevent that, every `load` counts itself in `readers` while it does this,
+/// and a writer only gives up the old `Arc` after it has seen that every load that
+/// might have read the old pointer has finished. There are two counters, so a steady
+/// stream of new loads (which count themselves in the other one) can't hold up a writer
+/// forever. Writers flip between them twice, since a load might have picked a
+/// counter right before one flip, but only started counting after it.
+pub struct AtomicArc<T> {
+    /// From `Arc::into_raw`. Represents one `Arc` owned by this `AtomicArc`.
+    ptr: AtomicPtr<T>,
+    /// Which of the two `readers` counters new loads use.
+    generation: AtomicUsize,
+    /// The number of loads in progress, per generation.
+    readers: [AtomicUsize; 2],
+    /// Only one writer at a time flips the generation.
+    writer: Mutex<()>,
+    _arc: PhantomData<Arc<T>>,
+}
+
+impl<T> AtomicArc<T> {
+    pub fn new(arc: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Arc::into_raw(arc) as *mut T),
+            generation: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+            _arc: PhantomData,
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        // Which counter doesn't matter for safety: a writer waits for both.
+        let g = self.generation.load(Relaxed);
+        self.readers[g].fetch_add(1, Relaxed);
+        // Pairs with the fence in `wait_for_readers`: if we see the old pointer,
+        // the writer that replaced it will see our increment of the readers counter.
+        fence(SeqCst);
+        // Acquire, to see the data of the `Arc` it points to.
+        let p = self.ptr.load(Acquire);
+        // Safety: We're counted in `readers`, so the `Arc` the pointer came
+        // from can't be dropped until we're done.
+        let arc = unsafe { clone_raw(p) };
+        // Release, so the writer's wait happens after our clone.
+        self.readers[g].fetch_sub(1, Release);
+        arc
+    }
+
+    pub fn store(&self, new: Arc<T>) {
+        drop(self.swap(new));
+    }
+
+    pub fn swap(&self, new: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock().unwrap();
+        // AcqRel: Release for loads to see the data of `new`, and Acquire for us to see that of `old`.
+        let old = self.ptr.swap(Arc::into_raw(new) as *mut T, AcqRel);
+        self.wait_for_readers();
+        // Safety: This was our `Arc`, and no load is still cloning it.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    /// Replaces the `Arc` with `new` if it still points to the same data as `current`.
+    ///
+    /// Returns the previous `Arc`, which is the same as `current` if it was replaced.
+    /// Otherwise, `new` is dropped.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock().unwrap();
+        // Only writers modify the pointer, and we're the only writer.
+        let p = self.ptr.load(Acquire);
+        if !ptr::eq(p, &**current) {
+            // Safety: Nobody can drop our `Arc` while we're the writer.
+            return unsafe { clone_raw(p) };
+        }
+        self.ptr.store(Arc::into_raw(new) as *mut T, Release);
+        self.wait_for_readers();
+        // Safety: This was our `Arc`, and no load is still cloning it.
+        unsafe { Arc::from_raw(p) }
+    }
+
+    pub fn into_inner(self) -> Arc<T> {
+        let this = ManuallyDrop::new(self);
+        // Safety: This is our `Arc`, and we're never going to use `this` again.
+        unsafe { Arc::from_raw(this.ptr.load(Acquire)) }
+    }
+
+    /// Waits for every load that started before the pointer was replaced.
+    fn wait_for_readers(&self) {
+        // See `load`.
+        fence(SeqCst);
+        for _ in 0..2 {
+            let g = self.generation.fetch_xor(1, Relaxed);
+            // Acquire, so a load's clone happens before we drop the old `Arc`.
+            while self.readers[g].load(Acquire) != 0 {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Creates a new `Arc` from a pointer from `Arc::into_raw`,
+/// without taking over the `Arc` it came from.
+unsafe fn clone_raw<T>(p: *const T) -> Arc<T> {
+    let arc = ManuallyDrop::new(Arc::from_raw(p));
+    Arc::clone(&arc)
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // Safety: This is our `Arc`, and nobody can be loading it anymore.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+#[test]
+fn main() {
+    let a = AtomicArc::new(Arc::new(1));
+    let one = a.load();
+    assert_eq!(*one, 1);
+    assert_eq!(*a.swap(Arc::new(2)), 1);
+    assert_eq!(*a.load(), 2);
+
+    // Doesn't point to the current value anymore.
+    let prev = a.compare_and_swap(&one, Arc::new(3));
+    assert_eq!(*prev, 2);
+    let prev = a.compare_and_swap(&prev, Arc::new(4));
+    assert_eq!(*prev, 2);
+    assert_eq!(*a.load(), 4);
+
+    a.store(Arc::new(5));
+    assert_eq!(*a.into_inner(), 5);
+    assert_eq!(Arc::strong_count(&one), 1);
+}
+
+#[test]
+fn concurrent() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    static NUM_ALIVE: AtomicUsize = AtomicUsize::new(0);
+
+    struct Config(usize);
+
+    impl Config {
+        fn new(n: usize) -> Arc<Config> {
+            NUM_ALIVE.fetch_add(1, Relaxed);
+            Arc::new(Config(n))
+        }
+    }
+
+    impl Drop for Config {
+        fn drop(&mut self) {
+            NUM_ALIVE.fetch_sub(1, Relaxed);
+        }
+    }
+
+    let a = AtomicArc::new(Config::new(0));
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut last = 0;
+                for _ in 0..10_000 {
+                    // Versions only ever go up.
+                    let n = a.load().0;
+                    assert!(n >= last);
+                    last = n;
+                }
+            });
+        }
+        s.spawn(|| {
+            for n in 1..=1000 {
+                a.store(Config::new(n));
+            }
+        });
+    });
+    assert_eq!(a.load().0, 1000);
+    assert_eq!(NUM_ALIVE.load(Relaxed), 1);
+    drop(a);
+    assert_eq!(NUM_ALIVE.load(Relaxed), 0);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{Arc as Shared, Tracked};
+    crate::sync::model(|| {
+        let a = Shared::new(AtomicArc::new(Arc::new(Tracked::new(1))));
+        let t = thread::spawn({
+            let a = a.clone();
+            move || a.store(Arc::new(Tracked::new(2)))
+        });
+        // The old one is only dropped once we're done cloning it.
+        let x = a.load();
+        assert!(x.get() == 1 || x.get() == 2);
+        t.join().unwrap();
+        assert_eq!(a.load().get(), 2);
+    });
+}
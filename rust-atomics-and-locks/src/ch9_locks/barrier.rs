@@ -0,0 +1,117 @@
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use crate::futex::{wait, wake_all};
+
+/// Lets a fixed number of threads wait for each other, over and over again.
+///
+/// The last thread to arrive starts a new generation, which is what the others wait for.
+/// That way, a thread that immediately waits on the barrier again is counted towards
+/// the next generation, without confusing threads that haven't woken up yet.
+pub struct Barrier {
+    /// The number of threads that have arrived in the current generation.
+    count: AtomicU32,
+    /// Incremented (wrapping) every time all threads have arrived. Waiting threads wait on this.
+    generation: AtomicU32,
+    num_threads: u32,
+}
+
+/// Returned by `Barrier::wait`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Whether this was the last thread to arrive. Exactly one thread per generation is.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    crate::sync::const_fn! {
+        /// Like `std::sync::Barrier`, a barrier for zero threads acts like one for one thread.
+        pub const fn new(num_threads: u32) -> Self {
+            Self {
+                count: AtomicU32::new(0),
+                generation: AtomicU32::new(0),
+                num_threads,
+            }
+        }
+    }
+
+    /// Waits until `num_threads` threads (including this one) are waiting.
+    pub fn wait(&self) -> BarrierWaitResult {
+        // Can't change until we arrive, since the current generation needs us.
+        let generation = self.generation.load(Acquire);
+        // AcqRel, so the leader sees everything the other threads did before arriving.
+        if self.count.fetch_add(1, AcqRel) + 1 >= self.num_threads {
+            // Nobody else touches the counter until the next generation starts.
+            self.count.store(0, Relaxed);
+            // Release, so the others see everything we (and they) did before arriving,
+            // and the reset counter.
+            self.generation.store(generation.wrapping_add(1), Release);
+            wake_all(&self.generation);
+            return BarrierWaitResult(true);
+        }
+        while self.generation.load(Acquire) == generation {
+            wait(&self.generation, generation);
+        }
+        BarrierWaitResult(false)
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+
+    let barrier = Barrier::new(4);
+    let leaders = AtomicU32::new(0);
+    let arrived = AtomicU32::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for round in 1..=100 {
+                    arrived.fetch_add(1, Relaxed);
+                    if barrier.wait().is_leader() {
+                        leaders.fetch_add(1, Relaxed);
+                    }
+                    // Everyone arrived for this round, and nobody arrives for
+                    // the next one until we've all passed the barrier again.
+                    assert_eq!(arrived.load(Relaxed), round * 4);
+                    if barrier.wait().is_leader() {
+                        leaders.fetch_add(1, Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(leaders.into_inner(), 200);
+    assert_eq!(barrier.generation.into_inner(), 200);
+
+    // A barrier for one thread never waits.
+    let barrier = Barrier::new(0);
+    assert!(barrier.wait().is_leader());
+    assert!(barrier.wait().is_leader());
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let barrier = Arc::new(Barrier::new(2));
+        let arrived = Arc::new(AtomicU32::new(0));
+        let t = thread::spawn({
+            let (barrier, arrived) = (barrier.clone(), arrived.clone());
+            move || {
+                arrived.fetch_add(1, Relaxed);
+                let leader = barrier.wait().is_leader();
+                assert_eq!(arrived.load(Relaxed), 2);
+                leader
+            }
+        });
+        arrived.fetch_add(1, Relaxed);
+        let leader = barrier.wait().is_leader();
+        assert_eq!(arrived.load(Relaxed), 2);
+        assert!(leader != t.join().unwrap());
+    });
+}
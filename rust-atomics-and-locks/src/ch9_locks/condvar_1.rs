@@ -1,6 +1,7 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::Relaxed;
+use crate::futex::{wait, wake_all, wake_one};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::Relaxed;
+use std::sync::LockResult;
 use super::mutex_3::MutexGuard;
 
 pub struct Condvar {
@@ -8,8 +9,10 @@ pub struct Condvar {
 }
 
 impl Condvar {
-    pub const fn new() -> Self {
-        Self { counter: AtomicU32::new(0) }
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self { counter: AtomicU32::new(0) }
+        }
     }
 
     pub fn notify_one(&self) {
@@ -22,7 +25,7 @@ impl Condvar {
         wake_all(&self.counter);
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
         let counter_value = self.counter.load(Relaxed);
 
         // Unlock the mutex by dropping the guard,
@@ -51,13 +54,13 @@ fn test_condvar() {
     thread::scope(|s| {
         s.spawn(|| {
             thread::sleep(Duration::from_secs(1));
-            *mutex.lock() = 123;
+            *mutex.lock().unwrap() = 123;
             condvar.notify_one();
         });
 
-        let mut m = mutex.lock();
+        let mut m = mutex.lock().unwrap();
         while *m < 100 {
-            m = condvar.wait(m);
+            m = condvar.wait(m).unwrap();
             wakeups += 1;
         }
 
@@ -68,3 +71,27 @@ fn test_condvar() {
     // while still allowing for a few spurious wake ups.
     assert!(wakeups < 10);
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use super::mutex_3::Mutex;
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let t = thread::spawn({
+            let pair = pair.clone();
+            move || {
+                *pair.0.lock().unwrap() = 123;
+                pair.1.notify_one();
+            }
+        });
+        let mut m = pair.0.lock().unwrap();
+        while *m == 0 {
+            m = pair.1.wait(m).unwrap();
+        }
+        assert_eq!(*m, 123);
+        drop(m);
+        t.join().unwrap();
+    });
+}
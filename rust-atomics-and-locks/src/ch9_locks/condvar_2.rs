@@ -1,49 +1,252 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::Relaxed;
+use std::ptr;
+use crate::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize};
+use crate::sync::atomic::Ordering::Relaxed;
+use std::sync::{LockResult, PoisonError};
+use std::time::{Duration, Instant};
 use super::mutex_3::MutexGuard;
+use super::reentrant_mutex::{ReentrantMutex, ReentrantMutexGuard};
+use super::wait_strategy::{SpinThenFutex, WaitStrategy};
+use crate::futex;
 
-pub struct Condvar {
+pub struct Condvar<W = SpinThenFutex> {
     counter: AtomicU32,
     num_waiters: AtomicUsize,
+    /// The futex of the mutex that all waiting threads have used, if they're
+    /// futex-based, so `notify_all` can requeue them onto it.
+    /// `no_requeue()` once different mutexes, or one that doesn't use its futex, have been used.
+    requeue_to: AtomicPtr<AtomicU32>,
+    strategy: W,
+}
+
+/// Only its address is used, for `Condvar::requeue_to`.
+/// (A plain `u32`, since loom's atomics can't be in a static.)
+static NO_REQUEUE: u32 = 0;
+
+fn no_requeue() -> *mut AtomicU32 {
+    &NO_REQUEUE as *const u32 as *mut AtomicU32
 }
 
 impl Condvar {
-    pub const fn new() -> Self {
-        Self {
-            counter: AtomicU32::new(0),
-            num_waiters: AtomicUsize::new(0),
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            // No spinning by default.
+            Self::with_strategy(SpinThenFutex::new(0))
+        }
+    }
+}
+
+impl<W> Condvar<W> {
+    crate::sync::const_fn! {
+        pub const fn with_strategy(strategy: W) -> Self {
+            Self {
+                counter: AtomicU32::new(0),
+                num_waiters: AtomicUsize::new(0),
+                requeue_to: AtomicPtr::new(ptr::null_mut()),
+                strategy,
+            }
         }
     }
+}
 
+impl<W: WaitStrategy> Condvar<W> {
     pub fn notify_one(&self) {
         if self.num_waiters.load(Relaxed) > 0 {
             self.counter.fetch_add(1, Relaxed);
-            wake_one(&self.counter);
+            self.strategy.wake_one(&self.counter);
         }
     }
 
+    /// Wakes up one waiting thread, and moves the others over to wait on the mutex,
+    /// if possible. Waking them all up would only make them all fight over the mutex,
+    /// while only one of them can get it. The others would go right back to sleep.
     pub fn notify_all(&self) {
         if self.num_waiters.load(Relaxed) > 0 {
             self.counter.fetch_add(1, Relaxed);
-            wake_all(&self.counter);
+            let mutex = self.requeue_to.load(Relaxed);
+            if mutex.is_null() || ptr::eq(mutex, no_requeue()) {
+                self.strategy.wake_all(&self.counter);
+            } else {
+                // Safety: Mutexes only stop existing when nobody is using them, like
+                // the threads waiting here. If there are none, requeue doesn't do anything.
+                // (Just like waking a mutex right after unlocking it, which might be
+                // the last thing anyone does with it.)
+                futex::requeue(&self.counter, unsafe { &*mutex }, 1, u32::MAX);
+            }
+        }
+    }
+
+    /// The mutex may use a different wait strategy than the condition variable.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait<'a, T, M: WaitStrategy>(
+        &self,
+        guard: MutexGuard<'a, T, M>,
+    ) -> LockResult<MutexGuard<'a, T, M>> {
+        match self.wait_deadline(guard, None) {
+            Ok((guard, _)) => Ok(guard),
+            Err(e) => Err(PoisonError::new(e.into_inner().0)),
+        }
+    }
+
+    /// Like `wait`, but gives up waiting after `timeout`.
+    ///
+    /// Just like `wait`, this might return spuriously, before the timeout.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait_timeout<'a, T, M: WaitStrategy>(
+        &self,
+        guard: MutexGuard<'a, T, M>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T, M>, WaitTimeoutResult)> {
+        self.wait_deadline(guard, Instant::now().checked_add(timeout))
+    }
+
+    /// Waits for as long as `condition` returns true.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait_while<'a, T, M: WaitStrategy>(
+        &self,
+        mut guard: MutexGuard<'a, T, M>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<MutexGuard<'a, T, M>> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Waits for as long as `condition` returns true, but for no longer than `timeout`.
+    ///
+    /// The result only reports a timeout if the condition still held at that point.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait_timeout_while<'a, T, M: WaitStrategy>(
+        &self,
+        mut guard: MutexGuard<'a, T, M>,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(MutexGuard<'a, T, M>, WaitTimeoutResult)> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            if !condition(&mut *guard) {
+                return Ok((guard, WaitTimeoutResult(false)));
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Ok((guard, WaitTimeoutResult(true)));
+            }
+            guard = self.wait_deadline(guard, deadline)?.0;
+        }
+    }
+
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    fn wait_deadline<'a, T, M: WaitStrategy>(
+        &self,
+        guard: MutexGuard<'a, T, M>,
+        deadline: Option<Instant>,
+    ) -> LockResult<(MutexGuard<'a, T, M>, WaitTimeoutResult)> {
+        let mutex = guard.mutex;
+        let (requeue, woken) = self.sleep(mutex.futex(), M::USES_FUTEX, || drop(guard), deadline);
+        let result = WaitTimeoutResult(!woken);
+        let guard = if requeue { mutex.lock_requeued() } else { mutex.lock() };
+        match guard {
+            Ok(guard) => Ok((guard, result)),
+            Err(e) => Err(PoisonError::new((e.into_inner(), result))),
         }
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    /// Like `wait`, but for a `ReentrantMutex`. While waiting, it's unlocked
+    /// completely, no matter how many guards this thread has for it.
+    /// All of them can be used again once this returns.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait_reentrant<'a, T, M: WaitStrategy>(
+        &self,
+        guard: ReentrantMutexGuard<'a, T, M>,
+    ) -> ReentrantMutexGuard<'a, T, M> {
+        let mutex = guard.mutex;
+        let mut count = 0;
+        let unlock = || count = ReentrantMutex::unlock_all(guard);
+        let (requeue, _) = self.sleep(mutex.futex(), M::USES_FUTEX, unlock, None);
+        mutex.relock(count, requeue)
+    }
+
+    /// Like `wait_while`, but for a `ReentrantMutex`.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn wait_while_reentrant<'a, T, M: WaitStrategy>(
+        &self,
+        mut guard: ReentrantMutexGuard<'a, T, M>,
+        mut condition: impl FnMut(&T) -> bool,
+    ) -> ReentrantMutexGuard<'a, T, M> {
+        while condition(&*guard) {
+            guard = self.wait_reentrant(guard);
+        }
+        guard
+    }
+
+    /// Unlocks the mutex with `unlock`, and waits to be notified.
+    ///
+    /// Returns whether the mutex needs to be locked with `lock_requeued`,
+    /// and whether we got woken up (rather than timing out).
+    fn sleep(
+        &self,
+        mutex_futex: &AtomicU32,
+        mutex_uses_futex: bool,
+        unlock: impl FnOnce(),
+        deadline: Option<Instant>,
+    ) -> (bool, bool) {
+        // Before we count as waiting, so `notify_all` can't requeue us onto the wrong mutex.
+        // Relaxed is enough, since it's done while holding the mutex, which the notifying
+        // thread needs to have locked too (at some point) for us to not miss the notification.
+        // Also when we can't be requeued, so `notify_all` stops requeueing the others.
+        let requeue = self.register_mutex(mutex_futex, W::USES_FUTEX && mutex_uses_futex);
+
         self.num_waiters.fetch_add(1, Relaxed);
 
         let counter_value = self.counter.load(Relaxed);
 
-        let mutex = guard.mutex;
-        drop(guard);
+        unlock();
 
-        wait(&self.counter, counter_value);
+        self.strategy.spin(|| self.counter.load(Relaxed) == counter_value);
+        let woken = self.strategy.wait(&self.counter, counter_value, deadline);
 
+        // Also when we timed out, such that notify_one and notify_all
+        // keep skipping the syscall once nobody is waiting anymore.
         self.num_waiters.fetch_sub(1, Relaxed);
 
-        mutex.lock()
+        (requeue, woken)
+    }
+}
+
+impl<W> Condvar<W> {
+    /// Returns whether `notify_all` can requeue waiting threads onto `futex`.
+    ///
+    /// `can_requeue` is false if this condvar or the mutex doesn't use a futex. That disables
+    /// requeueing for good: `notify_all` might wake up this thread rather than one that locks
+    /// the mutex it requeues onto, leaving the requeued ones waiting for an unlock that might
+    /// never come.
+    fn register_mutex(&self, futex: &AtomicU32, can_requeue: bool) -> bool {
+        if !can_requeue {
+            self.requeue_to.store(no_requeue(), Relaxed);
+            return false;
+        }
+        let futex = futex as *const AtomicU32 as *mut AtomicU32;
+        match self.requeue_to.compare_exchange(ptr::null_mut(), futex, Relaxed, Relaxed) {
+            Ok(_) => true,
+            Err(f) if f == futex => true,
+            Err(f) => {
+                if !ptr::eq(f, no_requeue()) {
+                    // Used with another mutex before. Never requeue anymore,
+                    // since threads waiting with either one might be waiting right now.
+                    self.requeue_to.store(no_requeue(), Relaxed);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Whether a timed wait on a `Condvar` returned because of its timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
     }
 }
 
@@ -61,13 +264,13 @@ fn test_condvar() {
     thread::scope(|s| {
         s.spawn(|| {
             thread::sleep(Duration::from_secs(1));
-            *mutex.lock() = 123;
+            *mutex.lock().unwrap() = 123;
             condvar.notify_one();
         });
 
-        let mut m = mutex.lock();
+        let mut m = mutex.lock().unwrap();
         while *m < 100 {
-            m = condvar.wait(m);
+            m = condvar.wait(m).unwrap();
             wakeups += 1;
         }
 
@@ -78,3 +281,202 @@ fn test_condvar() {
     // while still allowing for a few spurious wake ups.
     assert!(wakeups < 10);
 }
+
+#[test]
+fn test_wait_timeout() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    // Nobody notifies us, so this times out.
+    let start = Instant::now();
+    let (g, r) = condvar.wait_timeout(mutex.lock().unwrap(), Duration::from_millis(10)).unwrap();
+    assert!(r.timed_out());
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    // The waiter has been accounted for, so notifying is a no-op again.
+    assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+    drop(g);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 1..=10 {
+                thread::sleep(Duration::from_millis(1));
+                *mutex.lock().unwrap() = i;
+                condvar.notify_all();
+            }
+        });
+
+        let g = condvar.wait_while(mutex.lock().unwrap(), |m| *m < 5).unwrap();
+        assert!(*g >= 5);
+        drop(g);
+
+        let (g, r) = condvar.wait_timeout_while(
+            mutex.lock().unwrap(),
+            Duration::from_secs(10),
+            |m| *m < 10,
+        ).unwrap();
+        assert!(!r.timed_out());
+        assert_eq!(*g, 10);
+        drop(g);
+
+        let (g, r) = condvar.wait_timeout_while(
+            mutex.lock().unwrap(),
+            Duration::from_millis(10),
+            |m| *m < 11,
+        ).unwrap();
+        assert!(r.timed_out());
+        assert_eq!(*g, 10);
+    });
+
+    assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+}
+
+#[test]
+fn test_notify_all() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    for round in 1..=10 {
+        thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| {
+                    let _g = condvar.wait_while(mutex.lock().unwrap(), |m| *m < round).unwrap();
+                });
+            }
+            // Sometimes before, sometimes after some threads started waiting.
+            thread::sleep(Duration::from_millis(round as u64 % 3));
+            *mutex.lock().unwrap() = round;
+            condvar.notify_all();
+        });
+    }
+    assert!(ptr::eq(condvar.requeue_to.load(Relaxed), mutex.futex()));
+    assert_eq!(mutex.futex().load(Relaxed), 0);
+
+    // Using another mutex disables requeueing, but everything still works.
+    let other = Mutex::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            *other.lock().unwrap() = true;
+            condvar.notify_all();
+        });
+        let _g = condvar.wait_while(other.lock().unwrap(), |m| !*m).unwrap();
+    });
+    assert!(ptr::eq(condvar.requeue_to.load(Relaxed), no_requeue()));
+}
+
+#[test]
+fn test_notify_all_spin_mutex() {
+    use super::mutex_3::Mutex;
+    use super::wait_strategy::SpinOnly;
+    use std::thread;
+
+    // First a thread waiting with a mutex that doesn't use its futex, then one with a
+    // mutex that does. Requeueing onto the latter's futex would wake up the first thread
+    // instead of the second, which would then wait for that mutex to be unlocked forever.
+    let spin = Mutex::with_strategy(false, SpinOnly);
+    let futex = Mutex::new(false);
+    let condvar = Condvar::new();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let _g = condvar.wait_while(spin.lock().unwrap(), |m| !*m).unwrap();
+        });
+        thread::sleep(Duration::from_millis(10));
+        s.spawn(|| {
+            let _g = condvar.wait_while(futex.lock().unwrap(), |m| !*m).unwrap();
+        });
+        thread::sleep(Duration::from_millis(10));
+        *spin.lock().unwrap() = true;
+        *futex.lock().unwrap() = true;
+        condvar.notify_all();
+    });
+    assert!(ptr::eq(condvar.requeue_to.load(Relaxed), no_requeue()));
+}
+
+#[test]
+fn test_reentrant() {
+    use super::reentrant_mutex::ReentrantMutex;
+    use std::cell::Cell;
+    use std::thread;
+
+    let mutex = ReentrantMutex::new(Cell::new(0));
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let outer = mutex.lock();
+                let inner = mutex.lock();
+                // Both guards are released while waiting, or the notifying thread would never get in.
+                let inner = condvar.wait_while_reentrant(inner, |m| m.get() == 0);
+                outer.set(outer.get() + 1);
+                drop(outer);
+                // Still locked by `inner`.
+                assert!(s.spawn(|| mutex.try_lock().is_none()).join().unwrap());
+                inner.set(inner.get() + 1);
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+        let g = mutex.lock();
+        mutex.lock().set(1);
+        condvar.notify_all();
+        drop(g);
+    });
+    assert_eq!(mutex.lock().get(), 9);
+    assert!(ptr::eq(condvar.requeue_to.load(Relaxed), mutex.futex()));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use super::mutex_3::Mutex;
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let t = thread::spawn({
+            let pair = pair.clone();
+            move || {
+                *pair.0.lock().unwrap() = 123;
+                pair.1.notify_one();
+            }
+        });
+        let mut m = pair.0.lock().unwrap();
+        while *m == 0 {
+            m = pair.1.wait(m).unwrap();
+        }
+        assert_eq!(*m, 123);
+        drop(m);
+        t.join().unwrap();
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_notify_all() {
+    use super::mutex_3::Mutex;
+    use crate::sync::{thread, Arc};
+    // Three preemptions already take minutes. Two are enough to switch a waiter out
+    // between unlocking the mutex and waiting, and back in after `notify_all`,
+    // which is where a wakeup or a requeue would get lost.
+    crate::sync::model_bounded(2, || {
+        // Two waiters, so one of them gets requeued onto the mutex.
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let wait = {
+            let pair = pair.clone();
+            move || {
+                let _m = pair.1.wait_while(pair.0.lock().unwrap(), |m| !*m).unwrap();
+            }
+        };
+        let t = thread::spawn(wait.clone());
+        let u = thread::spawn(wait);
+        *pair.0.lock().unwrap() = true;
+        pair.1.notify_all();
+        t.join().unwrap();
+        u.join().unwrap();
+    });
+}
@@ -6,3 +6,12 @@ pub mod condvar_2;
 pub mod rwlock_1;
 pub mod rwlock_2;
 pub mod rwlock_3;
+pub mod reentrant_mutex;
+pub mod semaphore;
+pub mod stats;
+pub mod barrier;
+pub mod once;
+pub mod once_lock;
+pub mod wait_strategy;
+
+mod poison;
@@ -1,8 +1,8 @@
-use atomic_wait::{wait, wake_one};
-use std::cell::UnsafeCell;
+use crate::futex::{wait, wake_one};
+use crate::sync::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Release};
 
 pub struct Mutex<T> {
     /// 0: unlocked
@@ -33,10 +33,12 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 }
 
 impl<T> Mutex<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0), // unlocked state
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                state: AtomicU32::new(0), // unlocked state
+                value: UnsafeCell::new(value),
+            }
         }
     }
 
@@ -59,3 +61,11 @@ impl<T> Drop for MutexGuard<'_, T> {
         wake_one(&self.mutex.state);
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    // No preemptions: a thread waiting for the lock would swap in 1 forever under loom.
+    // (See `crate::sync`.) Still checks that unlocking hands over the counter.
+    crate::sync::check_lock_bounded(0, || Mutex::new(0), |m| *m.lock() += 1, |m| *m.lock());
+}
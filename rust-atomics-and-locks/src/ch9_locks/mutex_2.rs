@@ -1,8 +1,8 @@
-use atomic_wait::{wait, wake_one};
-use std::cell::UnsafeCell;
+use crate::futex::{wait, wake_one};
+use crate::sync::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 pub struct Mutex<T> {
     /// 0: unlocked
@@ -34,10 +34,12 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 }
 
 impl<T> Mutex<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0), // unlocked state
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                state: AtomicU32::new(0), // unlocked state
+                value: UnsafeCell::new(value),
+            }
         }
     }
 
@@ -58,3 +60,9 @@ impl<T> Drop for MutexGuard<'_, T> {
         }
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::check_lock(|| Mutex::new(0), |m| *m.lock() += 1, |m| *m.lock());
+}
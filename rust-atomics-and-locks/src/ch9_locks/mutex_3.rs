@@ -1,111 +1,298 @@
-use atomic_wait::{wait, wake_one};
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::{LockResult, TryLockError, TryLockResult};
+use std::time::{Duration, Instant};
+use super::poison;
+use crate::lock_order::{Access, LockNode};
+use super::stats::{Contention, HoldTimer, LockStats};
+use super::wait_strategy::{SpinThenFutex, WaitStrategy};
 
-pub struct Mutex<T> {
+pub struct Mutex<T, W = SpinThenFutex> {
     /// 0: unlocked
     /// 1: locked, no other threads waiting
     /// 2: locked, other threads waiting
     state: AtomicU32,
+    poison: poison::Flag,
+    stats: LockStats,
+    node: LockNode,
+    strategy: W,
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T, W> Sync for Mutex<T, W> where T: Send, W: Sync {}
 
-pub struct MutexGuard<'a, T> {
-    pub(crate) mutex: &'a Mutex<T>,
+pub struct MutexGuard<'a, T, W: WaitStrategy = SpinThenFutex> {
+    pub(crate) mutex: &'a Mutex<T, W>,
+    poison: poison::Guard,
+    held: HoldTimer,
 }
 
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+unsafe impl<T, W: WaitStrategy> Sync for MutexGuard<'_, T, W> where T: Sync, W: Sync {}
 
-impl<T> Deref for MutexGuard<'_, T> {
+impl<T, W: WaitStrategy> Deref for MutexGuard<'_, T, W> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.mutex.value.get() }
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T, W: WaitStrategy> DerefMut for MutexGuard<'_, T, W> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
 impl<T> Mutex<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0), // unlocked state
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self::with_strategy(value, SpinThenFutex::new(100))
         }
     }
+}
 
-    pub fn lock(&self) -> MutexGuard<T> {
+impl<T, W> Mutex<T, W> {
+    crate::sync::const_fn! {
+        pub const fn with_strategy(value: T, strategy: W) -> Self {
+            Self {
+                state: AtomicU32::new(0), // unlocked state
+                poison: poison::Flag::new(),
+                stats: LockStats::new(),
+                node: LockNode::new(),
+                strategy,
+                value: UnsafeCell::new(value),
+            }
+        }
+    }
+}
+
+impl<T, W: WaitStrategy> Mutex<T, W> {
+    /// Returns an error if another thread panicked while holding the lock.
+    /// The guard is still available through `PoisonError::into_inner`.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T, W>> {
+        self.node.before_lock(Access::Exclusive);
+        let mut contention = Contention::none();
         if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
             // The lock was already locked. :(
-            lock_contended(&self.state);
+            lock_contended(&self.state, &self.strategy, None, contention.start());
         }
-        MutexGuard { mutex: self }
+        self.guard(contention)
+    }
+
+    /// Locks the mutex only if that's possible without waiting.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T, W>> {
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(self.guard(Contention::none())?)
     }
-}
 
-fn lock_contended(state: &AtomicU32) {
-    let mut spin_count = 0;
+    /// Like `lock`, but gives up with `TryLockError::WouldBlock` after `timeout`.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn lock_timeout(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T, W>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.lock_deadline(deadline),
+            // Too far in the future to represent, so it won't ever expire.
+            None => Ok(self.lock()?),
+        }
+    }
 
-    while state.load(Relaxed) == 1 && spin_count < 100 {
-        spin_count += 1;
-        std::hint::spin_loop();
+    /// Like `lock`, but gives up with `TryLockError::WouldBlock` once `deadline` has passed.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn lock_deadline(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T, W>> {
+        self.node.before_lock(Access::Exclusive);
+        let mut contention = Contention::none();
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err()
+            && !lock_contended(&self.state, &self.strategy, Some(deadline), contention.start())
+        {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(self.guard(contention)?)
     }
 
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Like `lock`, but leaves the mutex marked as contended even if it wasn't locked,
+    /// since other threads might have been requeued onto it by `Condvar::notify_all`
+    /// without it knowing. Those only get woken up by unlocking a contended mutex.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub(crate) fn lock_requeued(&self) -> LockResult<MutexGuard<'_, T, W>> {
+        self.node.before_lock(Access::Exclusive);
+        let mut contention = Contention::none();
+        while self.state.swap(2, Acquire) != 0 {
+            contention.start().wait();
+            self.strategy.wait(&self.state, 2, None);
+        }
+        self.guard(contention)
+    }
+
+    /// The futex that waiting threads wait on.
+    pub(crate) fn futex(&self) -> &AtomicU32 {
+        &self.state
+    }
+
+    /// Creates the guard for a lock we've just acquired.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    fn guard(&self, contention: Contention) -> LockResult<MutexGuard<'_, T, W>> {
+        self.node.locked(Access::Exclusive);
+        let held = self.stats.acquired::<Self>(contention);
+        let guard = MutexGuard { mutex: self, poison: self.poison.guard(), held };
+        poison::map_result(&self.poison, guard)
+    }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> super::stats::StatsSnapshot {
+        self.stats.snapshot::<Self>()
+    }
+}
+
+/// Returns false if the deadline passed before we managed to lock the mutex.
+pub(crate) fn lock_contended(
+    state: &AtomicU32,
+    strategy: &impl WaitStrategy,
+    deadline: Option<Instant>,
+    contention: &mut Contention,
+) -> bool {
+    strategy.spin(|| {
+        contention.spin();
+        state.load(Relaxed) == 1
+    });
+
     if state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
-        return;
+        return true;
     }
 
     while state.swap(2, Acquire) != 0 {
-        wait(state, 2);
+        // We only ever give up right after a swap that found the mutex still
+        // locked. That leaves the state at 2 while another thread holds the
+        // lock, which only costs that thread a (possibly spurious) wake_one
+        // when unlocking. And since a thread that got woken up always swaps
+        // once more before giving up, it'll never swallow a wake-up that
+        // another waiting thread needed.
+        contention.wait();
+        if !strategy.wait(state, 2, deadline) {
+            return false;
+        }
     }
+
+    true
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T, W: WaitStrategy> Drop for MutexGuard<'_, T, W> {
     fn drop(&mut self) {
+        self.mutex.poison.done(&self.poison);
+        self.mutex.stats.released(&self.held);
+        self.mutex.node.unlocked();
         if self.mutex.state.swap(0, Release) == 2 {
-            wake_one(&self.mutex.state);
+            self.mutex.strategy.wake_one(&self.mutex.state);
         }
     }
 }
 
-// TODO (bench)
 #[test]
-fn main() {
-    use std::time::Instant;
+fn test_timeout() {
+    use std::thread;
+
     let m = Mutex::new(0);
-    std::hint::black_box(&m);
-    let start = Instant::now();
-    for _ in 0..5_000_000 {
-        *m.lock() += 1;
-    }
-    let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
+    thread::scope(|s| {
+        let g = m.lock().unwrap();
+        s.spawn(|| {
+            assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+            assert!(matches!(
+                m.lock_timeout(Duration::from_millis(10)),
+                Err(TryLockError::WouldBlock)
+            ));
+            // The timed-out waiter above left the state at 2.
+            assert_eq!(m.state.load(Relaxed), 2);
+        }).join().unwrap();
+        // A thread that's still waiting must get woken up by the unlock below.
+        let t = s.spawn(|| *m.lock().unwrap() += 1);
+        thread::sleep(Duration::from_millis(10));
+        drop(g);
+        t.join().unwrap();
+    });
+    assert_eq!(*m.try_lock().unwrap(), 1);
+
+    // Mix timed and untimed lockers, none of which should get stuck.
+    thread::scope(|s| {
+        for i in 0..8 {
+            let m = &m;
+            s.spawn(move || {
+                for _ in 0..10_000 {
+                    if i % 2 == 0 {
+                        *m.lock().unwrap() += 1;
+                    } else if let Ok(mut g) = m.lock_timeout(Duration::from_micros(1)) {
+                        *g += 1;
+                    }
+                }
+            });
+        }
+    });
+    assert!(*m.lock().unwrap() >= 40_001);
+    assert_eq!(m.state.load(Relaxed), 0);
 }
 
-// TODO (bench)
 #[test]
-fn main2() {
+fn test_poison() {
     use std::thread;
-    use std::time::Instant;
+
     let m = Mutex::new(0);
-    std::hint::black_box(&m);
-    let start = Instant::now();
     thread::scope(|s| {
-        for _ in 0..4 {
-            s.spawn(|| {
-                for _ in 0..5_000_000 {
-                    *m.lock() += 1;
-                }
-            });
-        }
+        let r = s.spawn(|| {
+            let mut g = m.lock().unwrap();
+            *g = 1;
+            panic!("oops");
+        }).join();
+        assert!(r.is_err());
+    });
+    assert!(m.is_poisoned());
+    // The lock still works, and the data is still reachable.
+    let Err(e) = m.lock() else { panic!("not poisoned") };
+    let g = e.into_inner();
+    assert_eq!(*g, 1);
+    drop(g);
+    assert!(m.try_lock().is_err());
+    assert_eq!(m.state.load(Relaxed), 0);
+    m.clear_poison();
+    assert_eq!(*m.lock().unwrap(), 1);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    crate::sync::check_lock(
+        || Mutex::new(0),
+        |m| *m.lock().unwrap() += 1,
+        |m| *m.lock().unwrap(),
+    );
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_give_up() {
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let m = Arc::new(Mutex::new(0));
+        let t = thread::spawn({
+            let m = m.clone();
+            move || *m.lock().unwrap() += 1
+        });
+        // Under loom, a timeout elapses right away, so both of these
+        // only get the lock if it's unlocked, or unlocked while trying.
+        let a = m.try_lock().map(|mut g| *g += 1).is_ok();
+        let b = m.lock_timeout(Duration::from_secs(1)).map(|mut g| *g += 1).is_ok();
+        t.join().unwrap();
+        assert_eq!(*m.lock().unwrap(), 1 + a as u32 + b as u32);
+        // Giving up didn't leave it locked, or keep the other thread from waking up.
+        assert_eq!(m.state.load(Relaxed), 0);
     });
-    let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
 }
@@ -0,0 +1,197 @@
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Release};
+use crate::futex::{wait, wake_all};
+
+/// Runs something exactly once, no matter how many threads try to at the same time.
+///
+/// Unlike the compare-and-exchange in `examples/ch2-13-lazy-one-time-init.rs`, where
+/// every thread that shows up early computes a value and only one of them gets to keep it,
+/// only one thread runs the closure, and the others wait for it on a futex.
+pub struct Once {
+    /// One of the states below. Waiting threads wait on this.
+    state: AtomicU32,
+}
+
+const INCOMPLETE: u32 = 0;
+/// A previous closure panicked. Like INCOMPLETE, but `call_once` refuses to try again.
+const POISONED: u32 = 1;
+const RUNNING: u32 = 2;
+/// RUNNING, with other threads waiting for it.
+const RUNNING_WAITING: u32 = 3;
+const COMPLETE: u32 = 4;
+
+/// Passed to the closure of `Once::call_once_force`.
+#[derive(Debug)]
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// Whether an earlier closure panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Once {
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self { state: AtomicU32::new(INCOMPLETE) }
+        }
+    }
+
+    /// Whether a closure has run to completion.
+    /// If so, everything it did is visible to us.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Acquire) == COMPLETE
+    }
+
+    /// Runs `f` if no closure has completed yet, or waits
+    /// for the one that's running right now to complete.
+    ///
+    /// Panics if an earlier closure panicked, since whatever it was
+    /// initializing might have been left half done.
+    /// Calling this from within `f` deadlocks.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.is_completed() {
+            return;
+        }
+        self.call(false, |_| f());
+    }
+
+    /// Like `call_once`, but tries again if an earlier closure panicked.
+    /// `f` gets to know whether that happened.
+    pub fn call_once_force(&self, f: impl FnOnce(&OnceState)) {
+        if self.is_completed() {
+            return;
+        }
+        self.call(true, f);
+    }
+
+    #[cold]
+    fn call(&self, ignore_poison: bool, f: impl FnOnce(&OnceState)) {
+        let mut state = self.state.load(Acquire);
+        loop {
+            match state {
+                COMPLETE => return,
+                POISONED if !ignore_poison => panic!("Once instance has previously been poisoned"),
+                INCOMPLETE | POISONED => {
+                    if let Err(s) = self.state.compare_exchange(state, RUNNING, Acquire, Acquire) {
+                        state = s;
+                        continue;
+                    }
+                    // Poisons the Once if `f` panics.
+                    let mut finish = Finish { once: self, state: POISONED };
+                    f(&OnceState { poisoned: state == POISONED });
+                    finish.state = COMPLETE;
+                    return;
+                }
+                RUNNING => {
+                    // Let the running thread know it needs to wake us up.
+                    if let Err(s) = self.state.compare_exchange(RUNNING, RUNNING_WAITING, Acquire, Acquire) {
+                        state = s;
+                        continue;
+                    }
+                    state = RUNNING_WAITING;
+                }
+                RUNNING_WAITING => {
+                    wait(&self.state, RUNNING_WAITING);
+                    state = self.state.load(Acquire);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sets the final state when dropped, even when unwinding.
+struct Finish<'a> {
+    once: &'a Once,
+    state: u32,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        // Release, so waiting threads see everything the closure did.
+        if self.once.state.swap(self.state, Release) == RUNNING_WAITING {
+            wake_all(&self.once.state);
+        }
+    }
+}
+
+#[test]
+fn main() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+
+    let once = Once::new();
+    let calls = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| {
+                once.call_once(|| {
+                    thread::sleep(Duration::from_millis(100));
+                    calls.fetch_add(1, Relaxed);
+                });
+                // Everyone waited for the one call to finish.
+                assert!(once.is_completed());
+                assert_eq!(calls.load(Relaxed), 1);
+            });
+        }
+    });
+    once.call_once(|| unreachable!());
+    once.call_once_force(|_| unreachable!());
+}
+
+#[test]
+fn poison() {
+    use std::thread;
+
+    let once = Once::new();
+    thread::scope(|s| {
+        let r = s.spawn(|| once.call_once(|| panic!("oops"))).join();
+        assert!(r.is_err());
+        // Refuses to try again.
+        let r = s.spawn(|| once.call_once(|| unreachable!())).join();
+        assert!(r.is_err());
+    });
+    assert!(!once.is_completed());
+    // Unless we ask for it.
+    let mut poisoned = false;
+    once.call_once_force(|state| poisoned = state.is_poisoned());
+    assert!(poisoned);
+    assert!(once.is_completed());
+    once.call_once(|| unreachable!());
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::atomic::AtomicU32;
+    use crate::sync::atomic::Ordering::Relaxed;
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let once = Arc::new((Once::new(), AtomicU32::new(0)));
+        let call = {
+            let once = once.clone();
+            move || {
+                once.0.call_once(|| {
+                    once.1.fetch_add(1, Relaxed);
+                });
+                // Relaxed, since returning from `call_once` must synchronize with the call.
+                assert_eq!(once.1.load(Relaxed), 1);
+            }
+        };
+        let t = thread::spawn(call.clone());
+        call();
+        t.join().unwrap();
+    });
+}
@@ -0,0 +1,231 @@
+use std::cell::Cell;
+use crate::sync::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use super::once::Once;
+
+/// A value that's initialized at most once, by whichever thread gets to it first.
+///
+/// Can replace a `static AtomicU64` or `AtomicPtr` that's lazily initialized with
+/// a compare-and-exchange (like `get_key` and `get_data` in the chapter 2 and 3 examples),
+/// for values that don't fit in an atomic or that are expensive to compute.
+///
+/// If the initializing closure panics, the next thread to get here tries again with its own.
+pub struct OnceLock<T> {
+    once: Once,
+    /// Initialized once `once` has completed.
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Sync requires Send, since any thread can be the one to initialize (and `get_mut` it later).
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self {
+                once: Once::new(),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            // Safety: Initialized, and never modified again while shared.
+            Some(unsafe { (*self.value.get_const()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.once.is_completed() {
+            Some(unsafe { self.value.get_mut().assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// Gives the value back if the lock was already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Returns the value, first initializing it with `f` if nobody has yet.
+    ///
+    /// Waits if another thread is initializing it right now.
+    /// Calling this from within `f` deadlocks.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        self.once.call_once_force(|_| {
+            // Safety: Only one thread at a time runs this, and nobody reads
+            // the value until `once` has completed.
+            unsafe { (*self.value.get()).write(f()) };
+        });
+        self.get().unwrap()
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    /// Takes the value out, leaving the lock uninitialized.
+    pub fn take(&mut self) -> Option<T> {
+        if self.once.is_completed() {
+            self.once = Once::new();
+            // Safety: It was initialized, and `once` now says it's not.
+            Some(unsafe { self.value.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value that's initialized by `F` the first time it's used.
+///
+/// Unlike `OnceLock`, there's only one closure, so if it panics,
+/// the `Lazy` is poisoned: every later use panics as well.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceLock<T>,
+    /// Taken by the thread that initializes `cell`.
+    init: Cell<Option<F>>,
+}
+
+// Safety: `init` is only used by the one thread that gets to initialize `cell`.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    crate::sync::const_fn! {
+        pub const fn new(f: F) -> Self {
+            Self { cell: OnceLock::new(), init: Cell::new(Some(f)) }
+        }
+    }
+
+    /// Returns the value, initializing it first if this is the first use.
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+// Not under loom, since its `OnceLock` can't be in a static there.
+#[cfg(not(loom))]
+#[test]
+fn main() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+
+    // Like `get_key` in `examples/ch2-13-lazy-one-time-init.rs`,
+    // but without generating a key we'd throw away.
+    static GENERATED: AtomicUsize = AtomicUsize::new(0);
+    fn get_key() -> u64 {
+        static KEY: OnceLock<u64> = OnceLock::new();
+        *KEY.get_or_init(|| {
+            thread::sleep(Duration::from_millis(100));
+            GENERATED.fetch_add(1, Relaxed);
+            123
+        })
+    }
+    thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| assert_eq!(get_key(), 123));
+        }
+    });
+    assert_eq!(GENERATED.load(Relaxed), 1);
+
+    let mut cell = OnceLock::new();
+    assert_eq!(cell.get(), None);
+    assert_eq!(cell.set(String::from("a")), Ok(()));
+    assert_eq!(cell.set(String::from("b")), Err(String::from("b")));
+    assert_eq!(cell.get_or_init(|| unreachable!()), "a");
+    cell.get_mut().unwrap().push('!');
+    assert_eq!(cell.take().as_deref(), Some("a!"));
+    assert_eq!(cell.get(), None);
+    cell.set(String::from("c")).unwrap();
+    assert_eq!(cell.into_inner().as_deref(), Some("c"));
+}
+
+#[test]
+fn retry() {
+    use std::thread;
+
+    let cell = OnceLock::new();
+    thread::scope(|s| {
+        let r = s.spawn(|| cell.get_or_init(|| panic!("oops"))).join();
+        assert!(r.is_err());
+    });
+    assert_eq!(cell.get(), None);
+    // The next thread gets to try again.
+    assert_eq!(cell.get_or_init(|| 1), &1);
+}
+
+#[cfg(not(loom))]
+#[test]
+fn lazy() {
+    use std::collections::HashMap;
+    use std::thread;
+
+    static MAP: Lazy<HashMap<u32, &str>> = Lazy::new(|| HashMap::from([(1, "one"), (2, "two")]));
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(MAP[&2], "two"));
+        }
+    });
+
+    let lazy = Lazy::new(|| -> u32 { panic!("oops") });
+    thread::scope(|s| {
+        assert!(s.spawn(|| *lazy).join().is_err());
+        // Poisoned, since the closure is gone.
+        assert!(s.spawn(|| *lazy).join().is_err());
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc, Tracked};
+    crate::sync::model(|| {
+        let cell = Arc::new(OnceLock::new());
+        let t = thread::spawn({
+            let cell = cell.clone();
+            move || cell.get_or_init(|| Tracked::new(1)).get()
+        });
+        let a = cell.get_or_init(|| Tracked::new(2)).get();
+        assert_eq!(a, t.join().unwrap());
+    });
+}
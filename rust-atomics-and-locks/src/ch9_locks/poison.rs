@@ -0,0 +1,57 @@
+//! Lock poisoning, the same way the standard library does it:
+//! a guard that gets dropped during a panic marks its lock as poisoned,
+//! so later lockers get an `Err(PoisonError)` wrapping their guard.
+
+use crate::sync::atomic::AtomicBool;
+use crate::sync::atomic::Ordering::Relaxed;
+use std::sync::{LockResult, PoisonError};
+use std::thread;
+
+pub(crate) struct Flag {
+    failed: AtomicBool,
+}
+
+/// Stored in a lock guard, to remember whether the thread was
+/// already panicking when it took the lock.
+pub(crate) struct Guard {
+    panicking: bool,
+}
+
+impl Flag {
+    crate::sync::const_fn! {
+        pub const fn new() -> Self {
+            Self { failed: AtomicBool::new(false) }
+        }
+    }
+
+    /// To be called right after locking.
+    pub fn guard(&self) -> Guard {
+        Guard { panicking: thread::panicking() }
+    }
+
+    /// To be called right before unlocking.
+    ///
+    /// Relaxed is enough, since the unlocking (Release) and
+    /// next locking (Acquire) of the lock order this for us.
+    pub fn done(&self, guard: &Guard) {
+        if !guard.panicking && thread::panicking() {
+            self.failed.store(true, Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        self.failed.load(Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.failed.store(false, Relaxed);
+    }
+}
+
+pub(crate) fn map_result<G>(flag: &Flag, guard: G) -> LockResult<G> {
+    if flag.get() {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
@@ -0,0 +1,240 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use crate::sync::atomic::{AtomicU32, AtomicU64};
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use super::mutex_3::lock_contended;
+use super::stats::Contention;
+use super::wait_strategy::{SpinThenFutex, WaitStrategy};
+use crate::lock_order::{Access, LockNode};
+
+/// A mutex that the thread holding it can lock again, without deadlocking.
+///
+/// Since a thread can have several guards at once, they only give shared access.
+/// Use a `Cell` or `RefCell` to modify the value.
+pub struct ReentrantMutex<T, W = SpinThenFutex> {
+    /// The same 0/1/2 states as `mutex_3::Mutex`.
+    state: AtomicU32,
+    /// The `current_thread` that holds the lock, or 0 if it isn't locked.
+    owner: AtomicU64,
+    /// How many guards the owner has. Only used by the owner.
+    count: Cell<u32>,
+    node: LockNode,
+    strategy: W,
+    value: T,
+}
+
+// Only one thread can access the value at a time, so it doesn't need to be Sync.
+unsafe impl<T, W> Sync for ReentrantMutex<T, W> where T: Send, W: Sync {}
+
+pub struct ReentrantMutexGuard<'a, T, W: WaitStrategy = SpinThenFutex> {
+    pub(crate) mutex: &'a ReentrantMutex<T, W>,
+    /// Must be dropped on the thread that owns the lock.
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T, W: WaitStrategy> Sync for ReentrantMutexGuard<'_, T, W> where T: Sync, W: Sync {}
+
+impl<T, W: WaitStrategy> Deref for ReentrantMutexGuard<'_, T, W> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.mutex.value
+    }
+}
+
+/// A number unique to the current thread. Never 0, and never reused for another thread.
+/// (Unlike `ThreadId`, it fits in an atomic, and unlike the address of
+/// a thread local, it isn't reused once the thread exits.)
+fn current_thread() -> u64 {
+    // Not a loom atomic, since this outlives a loom model.
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    crate::sync::thread_local! {
+        static ID: u64 = NEXT.fetch_add(1, Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+impl<T> ReentrantMutex<T> {
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self::with_strategy(value, SpinThenFutex::new(100))
+        }
+    }
+}
+
+impl<T, W> ReentrantMutex<T, W> {
+    crate::sync::const_fn! {
+        pub const fn with_strategy(value: T, strategy: W) -> Self {
+            Self {
+                state: AtomicU32::new(0),
+                owner: AtomicU64::new(0),
+                count: Cell::new(0),
+                node: LockNode::new(),
+                strategy,
+                value,
+            }
+        }
+    }
+}
+
+impl<T, W: WaitStrategy> ReentrantMutex<T, W> {
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T, W> {
+        let this = current_thread();
+        if let Some(guard) = self.lock_again(this) {
+            return guard;
+        }
+        self.node.before_lock(Access::Exclusive);
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            lock_contended(&self.state, &self.strategy, None, &mut Contention::none());
+        }
+        self.locked(this, 1)
+    }
+
+    /// Locks the mutex only if that's possible without waiting,
+    /// which is always the case if this thread already holds it.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T, W>> {
+        let this = current_thread();
+        if let Some(guard) = self.lock_again(this) {
+            return Some(guard);
+        }
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            return None;
+        }
+        Some(self.locked(this, 1))
+    }
+
+    /// Another guard, if this thread already holds the lock.
+    /// This doesn't touch the state, so never needs a syscall.
+    fn lock_again(&self, this: u64) -> Option<ReentrantMutexGuard<'_, T, W>> {
+        // Relaxed is enough: only this thread ever stores its own id, and
+        // it always resets it before unlocking. If it's our id, we hold the lock.
+        if self.owner.load(Relaxed) != this {
+            return None;
+        }
+        let count = self.count.get().checked_add(1).expect("too many guards");
+        self.count.set(count);
+        Some(ReentrantMutexGuard { mutex: self, _not_send: PhantomData })
+    }
+
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    fn locked(&self, this: u64, count: u32) -> ReentrantMutexGuard<'_, T, W> {
+        self.node.locked(Access::Exclusive);
+        self.owner.store(this, Relaxed);
+        self.count.set(count);
+        ReentrantMutexGuard { mutex: self, _not_send: PhantomData }
+    }
+
+    fn unlock(&self) {
+        self.node.unlocked();
+        self.owner.store(0, Relaxed);
+        if self.state.swap(0, Release) == 2 {
+            self.strategy.wake_one(&self.state);
+        }
+    }
+
+    /// Unlocks the mutex completely, no matter how many guards this thread has,
+    /// for `Condvar` to wait. Returns the number of guards, for `relock`.
+    pub(crate) fn unlock_all(guard: ReentrantMutexGuard<'_, T, W>) -> u32 {
+        let count = guard.mutex.count.replace(0);
+        guard.mutex.unlock();
+        std::mem::forget(guard);
+        count
+    }
+
+    /// Locks it again after `unlock_all`, with all the guards from before.
+    ///
+    /// If `requeued`, just like `Mutex::lock_requeued`, this leaves it marked as contended.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub(crate) fn relock(&self, count: u32, requeued: bool) -> ReentrantMutexGuard<'_, T, W> {
+        self.node.before_lock(Access::Exclusive);
+        if requeued {
+            while self.state.swap(2, Acquire) != 0 {
+                self.strategy.wait(&self.state, 2, None);
+            }
+        } else if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            lock_contended(&self.state, &self.strategy, None, &mut Contention::none());
+        }
+        self.locked(current_thread(), count)
+    }
+
+    /// The futex that waiting threads wait on.
+    pub(crate) fn futex(&self) -> &AtomicU32 {
+        &self.state
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for ReentrantMutexGuard<'_, T, W> {
+    fn drop(&mut self) {
+        let count = self.mutex.count.get() - 1;
+        self.mutex.count.set(count);
+        if count == 0 {
+            self.mutex.unlock();
+        }
+    }
+}
+
+#[test]
+fn main() {
+    use std::cell::RefCell;
+    use std::thread;
+
+    let m = ReentrantMutex::new(RefCell::new(Vec::new()));
+    thread::scope(|s| {
+        for i in 0..4 {
+            let m = &m;
+            s.spawn(move || {
+                for _ in 0..1000 {
+                    let a = m.lock();
+                    let b = m.lock();
+                    let c = m.try_lock().unwrap();
+                    a.borrow_mut().push(i);
+                    // Guards don't have to be dropped in order.
+                    drop(a);
+                    b.borrow_mut().push(i);
+                    drop(b);
+                    c.borrow_mut().push(i);
+                }
+            });
+        }
+        let g = m.lock();
+        s.spawn(|| assert!(m.try_lock().is_none())).join().unwrap();
+        drop(g);
+    });
+    let g = m.lock();
+    let h = m.lock();
+    // Locking it again didn't touch the futex.
+    assert_eq!(m.state.load(Relaxed), 1);
+    drop(h);
+    let v = g.borrow();
+    assert_eq!(v.len(), 12_000);
+    // Nobody got in between while the lock was held.
+    assert!(v.chunks(3).all(|c| c[0] == c[1] && c[1] == c[2]));
+    drop(v);
+    drop(g);
+    assert_eq!(m.state.load(Relaxed), 0);
+    assert_eq!(m.owner.load(Relaxed), 0);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc, UnsafeCell};
+    crate::sync::model(|| {
+        let m = Arc::new(ReentrantMutex::new(UnsafeCell::new(0)));
+        let increment = {
+            let m = m.clone();
+            move || {
+                let a = m.lock();
+                let b = m.lock();
+                drop(a);
+                unsafe { *b.get() += 1 };
+            }
+        };
+        let t = thread::spawn(increment.clone());
+        increment();
+        t.join().unwrap();
+        assert_eq!(unsafe { *m.lock().get() }, 2);
+    });
+}
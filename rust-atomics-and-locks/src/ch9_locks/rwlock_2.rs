@@ -1,8 +1,8 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::cell::UnsafeCell;
+use crate::futex::{wait, wake_all, wake_one};
+use crate::sync::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 pub struct RwLock<T> {
     /// The number of readers, or u32::MAX if write-locked.
@@ -15,11 +15,13 @@ pub struct RwLock<T> {
 unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
 
 impl<T> RwLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            writer_wake_counter: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            Self {
+                state: AtomicU32::new(0),
+                writer_wake_counter: AtomicU32::new(0),
+                value: UnsafeCell::new(value),
+            }
         }
     }
 
@@ -81,7 +83,7 @@ impl<T> DerefMut for WriteGuard<'_, T> {
 impl<T> Deref for ReadGuard<'_, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
+        unsafe { &*self.rwlock.value.get_const() }
     }
 }
 
@@ -102,3 +104,24 @@ impl<T> Drop for WriteGuard<'_, T> {
         wake_all(&self.rwlock.state);
     }
 }
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    // Every interleaving takes two minutes. Five preemptions are enough to switch
+    // out the writer at any point in its lock or unlock, and the main thread at
+    // any two points around it, such as right before and after it starts waiting.
+    crate::sync::model_bounded(5, || {
+        let l = Arc::new(RwLock::new(0));
+        let t = thread::spawn({
+            let l = l.clone();
+            move || *l.write() += 1
+        });
+        let r = *l.read();
+        assert!(r == 0 || r == 1);
+        *l.write() += 1;
+        t.join().unwrap();
+        assert_eq!(*l.read(), 2);
+    });
+}
@@ -1,10 +1,16 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::cell::UnsafeCell;
+use crate::sync::UnsafeCell;
+use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::atomic::{fence, AtomicBool, AtomicU32};
+use crate::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::LockResult;
+use super::mutex_3::lock_contended;
+use super::poison;
+use crate::lock_order::{Access, LockNode};
+use super::stats::{Contention, HoldTimer, LockStats};
+use super::wait_strategy::{SpinThenFutex, WaitStrategy};
 
-pub struct RwLock<T> {
+pub struct RwLock<T, W = SpinThenFutex> {
     /// The number of read locks times two, plus one if there's a writer waiting.
     /// u32::MAX if write locked.
     ///
@@ -13,21 +19,83 @@ pub struct RwLock<T> {
     state: AtomicU32,
     /// Incremented to wake up writers.
     writer_wake_counter: AtomicU32,
+    /// A mutex (with the same 0/1/2 states as mutex_3) held by the
+    /// upgradable reader, if any, to exclude other upgradable readers.
+    /// The upgradable reader also holds a normal read lock, which excludes writers.
+    upgradable: AtomicU32,
+    /// Set while an upgradable reader waits for the other readers to leave,
+    /// to ask the last one of them to wake it up.
+    upgrading: AtomicBool,
+    /// Set when a writer panics. Readers can't modify the data, so they never poison.
+    poison: poison::Flag,
+    stats: LockStats,
+    node: LockNode,
+    strategy: W,
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+unsafe impl<T, W> Sync for RwLock<T, W> where T: Send + Sync, W: Sync {}
 
 impl<T> RwLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            writer_wake_counter: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
+    crate::sync::const_fn! {
+        pub const fn new(value: T) -> Self {
+            // No spinning by default.
+            Self::with_strategy(value, SpinThenFutex::new(0))
         }
     }
+}
+
+impl<T, W> RwLock<T, W> {
+    crate::sync::const_fn! {
+        pub const fn with_strategy(value: T, strategy: W) -> Self {
+            Self {
+                state: AtomicU32::new(0),
+                writer_wake_counter: AtomicU32::new(0),
+                upgradable: AtomicU32::new(0),
+                upgrading: AtomicBool::new(false),
+                poison: poison::Flag::new(),
+                stats: LockStats::new(),
+                node: LockNode::new(),
+                strategy,
+                value: UnsafeCell::new(value),
+            }
+        }
+    }
+}
+
+impl<T, W: WaitStrategy> RwLock<T, W> {
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn read(&self) -> LockResult<ReadGuard<'_, T, W>> {
+        self.node.before_lock(Access::Shared);
+        let mut contention = Contention::none();
+        self.lock_read(&mut contention);
+        self.node.locked(Access::Shared);
+        let held = self.stats.acquired::<Self>(contention);
+        poison::map_result(&self.poison, ReadGuard { rwlock: self, held })
+    }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    /// Locks for reading, in a way that can later be upgraded to a write lock
+    /// without letting any writer in between.
+    ///
+    /// This coexists with plain readers, but there can be only
+    /// one upgradable reader at a time.
+    ///
+    /// Just like with `read`, taking another read lock while holding
+    /// this one deadlocks if a writer starts waiting in between.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn upgradable_read(&self) -> LockResult<UpgradableReadGuard<'_, T, W>> {
+        self.node.before_lock(Access::Upgradable);
+        let mut contention = Contention::none();
+        if self.upgradable.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            lock_contended(&self.upgradable, &self.strategy, None, contention.start());
+        }
+        self.lock_read(&mut contention);
+        self.node.locked(Access::Upgradable);
+        let held = self.stats.acquired::<Self>(contention);
+        poison::map_result(&self.poison, UpgradableReadGuard { rwlock: self, held })
+    }
+
+    fn lock_read(&self, contention: &mut Contention) {
         let mut s = self.state.load(Relaxed);
         loop {
             if s % 2 == 0 { // Even.
@@ -35,18 +103,30 @@ impl<T> RwLock<T> {
                 match self.state.compare_exchange_weak(
                     s, s + 2, Acquire, Relaxed
                 ) {
-                    Ok(_) => return ReadGuard { rwlock: self },
+                    Ok(_) => return,
                     Err(e) => s = e,
                 }
             }
             if s % 2 == 1 { // Odd.
-                wait(&self.state, s);
+                contention.start();
+                self.strategy.spin(|| {
+                    contention.spin();
+                    self.state.load(Relaxed) % 2 == 1
+                });
+                s = self.state.load(Relaxed);
+            }
+            if s % 2 == 1 { // Still odd.
+                contention.wait();
+                self.strategy.wait(&self.state, s, None);
                 s = self.state.load(Relaxed);
             }
         }
     }
 
-    pub fn write(&self) -> WriteGuard<T> {
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn write(&self) -> LockResult<WriteGuard<'_, T, W>> {
+        self.node.before_lock(Access::Exclusive);
+        let mut contention = Contention::none();
         let mut s = self.state.load(Relaxed);
         loop {
             // Try to lock if unlocked.
@@ -54,7 +134,12 @@ impl<T> RwLock<T> {
                 match self.state.compare_exchange(
                     s, u32::MAX, Acquire, Relaxed
                 ) {
-                    Ok(_) => return WriteGuard { rwlock: self },
+                    Ok(_) => {
+                        self.node.locked(Access::Exclusive);
+                        let held = self.stats.acquired::<Self>(contention);
+                        let guard = WriteGuard { rwlock: self, poison: self.poison.guard(), held };
+                        return poison::map_result(&self.poison, guard);
+                    }
                     Err(e) => { s = e; continue; }
                 }
             }
@@ -68,62 +153,321 @@ impl<T> RwLock<T> {
                 }
             }
             // Wait, if it's still locked
+            contention.start();
+            self.strategy.spin(|| {
+                contention.spin();
+                self.state.load(Relaxed) >= 2
+            });
             let w = self.writer_wake_counter.load(Acquire);
             s = self.state.load(Relaxed);
-            if s >= 2 {
-                wait(&self.writer_wake_counter, w);
+            // Only while it's still odd: if a writer unlocked (or downgraded) and
+            // readers got in since we set the bit, nobody would wake us up,
+            // so we have to go back and set it again.
+            if s >= 2 && s % 2 == 1 {
+                contention.wait();
+                self.strategy.wait(&self.writer_wake_counter, w, None);
                 s = self.state.load(Relaxed);
             }
         }
     }
+
+    fn unlock_read(&self) {
+        // Decrement the state by 2 to remove one read-lock.
+        match self.state.fetch_sub(2, Release) {
+            3 => {
+                // If we decremented from 3 to 1, that means
+                // the RwLock is now unlocked _and_ there is
+                // a waiting writer, which we wake up.
+                self.writer_wake_counter.fetch_add(1, Release);
+                self.strategy.wake_one(&self.writer_wake_counter);
+            }
+            5 => {
+                // If we decremented from 5 to 3, the one read-lock that's left might
+                // belong to an upgradable reader that's waiting for us to leave.
+                // Acquire, to see the `upgrading` flag from before it set the odd bit.
+                fence(Acquire);
+                if self.upgrading.load(Relaxed) {
+                    self.writer_wake_counter.fetch_add(1, Release);
+                    // There might be writers waiting too, so we can't
+                    // know which one to wake up. They'll go back to sleep.
+                    self.strategy.wake_all(&self.writer_wake_counter);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn unlock_upgradable(&self) {
+        if self.upgradable.swap(0, Release) == 2 {
+            self.strategy.wake_one(&self.upgradable);
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> super::stats::StatsSnapshot {
+        self.stats.snapshot::<Self>()
+    }
 }
 
-pub struct ReadGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
+pub struct ReadGuard<'a, T, W: WaitStrategy = SpinThenFutex> {
+    rwlock: &'a RwLock<T, W>,
+    held: HoldTimer,
 }
 
-pub struct WriteGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
+pub struct WriteGuard<'a, T, W: WaitStrategy = SpinThenFutex> {
+    rwlock: &'a RwLock<T, W>,
+    poison: poison::Guard,
+    held: HoldTimer,
 }
 
-impl<T> Deref for WriteGuard<'_, T> {
+pub struct UpgradableReadGuard<'a, T, W: WaitStrategy = SpinThenFutex> {
+    rwlock: &'a RwLock<T, W>,
+    held: HoldTimer,
+}
+
+impl<'a, T, W: WaitStrategy> UpgradableReadGuard<'a, T, W> {
+    /// Turns this read lock into a write lock, waiting for all other readers to leave.
+    ///
+    /// No writer can get in between, since writers can't lock the RwLock
+    /// while we still hold our read lock, and no other upgradable reader exists.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn upgrade(self) -> WriteGuard<'a, T, W> {
+        let rwlock = self.rwlock;
+        rwlock.node.changed(Access::Upgradable, Access::Exclusive);
+        // Still the same lock, as far as statistics are concerned.
+        let held = self.held;
+        mem::forget(self);
+        rwlock.upgrading.store(true, Relaxed);
+        loop {
+            let w = rwlock.writer_wake_counter.load(Acquire);
+            // Block new readers, by making sure the state is odd.
+            // Release, to make `upgrading` visible to the reader that decrements to 3.
+            let s = rwlock.state.fetch_or(1, AcqRel) | 1;
+            // Only our own read-lock left? Then nothing can change the state but us.
+            if s == 3 && rwlock.state.compare_exchange(
+                3, u32::MAX, Acquire, Relaxed
+            ).is_ok() {
+                break;
+            }
+            rwlock.strategy.spin(|| rwlock.state.load(Relaxed) > 3);
+            if rwlock.state.load(Relaxed) > 3 {
+                rwlock.strategy.wait(&rwlock.writer_wake_counter, w, None);
+            }
+        }
+        rwlock.upgrading.store(false, Relaxed);
+        // We're a writer now, which excludes upgradable readers by itself.
+        rwlock.unlock_upgradable();
+        WriteGuard { rwlock, poison: rwlock.poison.guard(), held }
+    }
+}
+
+impl<T, W: WaitStrategy> Deref for UpgradableReadGuard<'_, T, W> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get_const() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for UpgradableReadGuard<'_, T, W> {
+    fn drop(&mut self) {
+        self.rwlock.stats.released(&self.held);
+        self.rwlock.node.unlocked();
+        self.rwlock.unlock_read();
+        self.rwlock.unlock_upgradable();
+    }
+}
+
+impl<'a, T, W: WaitStrategy> WriteGuard<'a, T, W> {
+    /// Turns this write lock into a read lock, without letting any writer in between.
+    pub fn downgrade(self) -> ReadGuard<'a, T, W> {
+        let rwlock = self.rwlock;
+        rwlock.poison.done(&self.poison);
+        let held = self.held;
+        mem::forget(self);
+        // One read-lock: ours. This clears the writer-waiting bit,
+        // so we wake up the waiting writers to set it again,
+        // just like when unlocking.
+        rwlock.state.store(2, Release);
+        rwlock.writer_wake_counter.fetch_add(1, Release);
+        rwlock.strategy.wake_one(&rwlock.writer_wake_counter);
+        rwlock.strategy.wake_all(&rwlock.state);
+        rwlock.node.changed(Access::Exclusive, Access::Shared);
+        ReadGuard { rwlock, held }
+    }
+}
+
+impl<T, W: WaitStrategy> Deref for WriteGuard<'_, T, W> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.rwlock.value.get() }
     }
 }
 
-impl<T> DerefMut for WriteGuard<'_, T> {
+impl<T, W: WaitStrategy> DerefMut for WriteGuard<'_, T, W> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.rwlock.value.get() }
     }
 }
 
-impl<T> Deref for ReadGuard<'_, T> {
+impl<T, W: WaitStrategy> Deref for ReadGuard<'_, T, W> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
+        unsafe { &*self.rwlock.value.get_const() }
     }
 }
 
-impl<T> Drop for ReadGuard<'_, T> {
+impl<T, W: WaitStrategy> Drop for ReadGuard<'_, T, W> {
     fn drop(&mut self) {
-        // Decrement the state by 2 to remove one read-lock.
-        if self.rwlock.state.fetch_sub(2, Release) == 3 {
-            // If we decremented from 3 to 1, that means
-            // the RwLock is now unlocked _and_ there is
-            // a waiting writer, which we wake up.
-            self.rwlock.writer_wake_counter.fetch_add(1, Release);
-            wake_one(&self.rwlock.writer_wake_counter);
-        }
+        self.rwlock.stats.released(&self.held);
+        self.rwlock.node.unlocked();
+        self.rwlock.unlock_read();
     }
 }
 
-impl<T> Drop for WriteGuard<'_, T> {
+impl<T, W: WaitStrategy> Drop for WriteGuard<'_, T, W> {
     fn drop(&mut self) {
+        self.rwlock.poison.done(&self.poison);
+        self.rwlock.stats.released(&self.held);
+        self.rwlock.node.unlocked();
         self.rwlock.state.store(0, Release);
         self.rwlock.writer_wake_counter.fetch_add(1, Release);
-        wake_one(&self.rwlock.writer_wake_counter);
-        wake_all(&self.rwlock.state);
+        self.rwlock.strategy.wake_one(&self.rwlock.writer_wake_counter);
+        self.rwlock.strategy.wake_all(&self.rwlock.state);
     }
 }
+
+#[test]
+fn test_poison() {
+    use std::thread;
+
+    let l = RwLock::new(0);
+    thread::scope(|s| {
+        // Panicking while only reading doesn't poison.
+        let r = s.spawn(|| {
+            let _g = l.read().unwrap();
+            panic!("oops");
+        }).join();
+        assert!(r.is_err());
+        assert!(!l.is_poisoned());
+
+        let r = s.spawn(|| {
+            let mut g = l.write().unwrap();
+            *g = 1;
+            panic!("oops");
+        }).join();
+        assert!(r.is_err());
+    });
+    assert!(l.is_poisoned());
+    // Both the lock and the data are still usable.
+    let Err(e) = l.read() else { panic!("not poisoned") };
+    assert_eq!(*e.into_inner(), 1);
+    let Err(e) = l.write() else { panic!("not poisoned") };
+    *e.into_inner() += 1;
+    assert_eq!(l.state.load(Relaxed), 0);
+    l.clear_poison();
+    assert_eq!(*l.read().unwrap(), 2);
+}
+
+#[test]
+fn test_upgrade() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    let l = RwLock::new(0);
+
+    // Upgradable readers coexist with plain readers, but not with each other.
+    let active = AtomicUsize::new(0);
+    thread::scope(|s| {
+        let g = l.upgradable_read().unwrap();
+        let r = l.read().unwrap();
+        for _ in 0..2 {
+            s.spawn(|| {
+                let _g = l.upgradable_read().unwrap();
+                assert_eq!(active.fetch_add(1, Relaxed), 0);
+                thread::sleep(Duration::from_millis(5));
+                active.fetch_sub(1, Relaxed);
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(active.load(Relaxed), 0);
+        drop(r);
+        drop(g);
+    });
+
+    // No writer gets in between reading and upgrading, so no increments get lost.
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let g = l.upgradable_read().unwrap();
+                    let v = *g;
+                    *g.upgrade() = v + 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    *l.write().unwrap() += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    // Readers the upgradable reader has to wait for when upgrading.
+                    drop(l.read().unwrap());
+                }
+            });
+        }
+    });
+    assert_eq!(*l.read().unwrap(), 8000);
+    assert_eq!(l.state.load(Relaxed), 0);
+
+    // Downgrading keeps the value, and lets a waiting writer in afterwards.
+    thread::scope(|s| {
+        let mut g = l.write().unwrap();
+        let t = s.spawn(|| *l.write().unwrap() += 1);
+        thread::sleep(Duration::from_millis(10));
+        *g = 100;
+        let r = g.downgrade();
+        assert_eq!(*r, 100);
+        drop(r);
+        t.join().unwrap();
+    });
+    assert_eq!(*l.read().unwrap(), 101);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+    // Every interleaving takes more than five minutes, and four preemptions take fifteen
+    // seconds. Three are enough for the other thread's read or write to start in the
+    // middle of an upgrade or downgrade, and for either side to go back to the other.
+    crate::sync::model_bounded(3, || {
+        let l = Arc::new(RwLock::new(0));
+        let t = thread::spawn({
+            let l = l.clone();
+            move || {
+                let r = *l.read().unwrap();
+                *l.write().unwrap() += 1;
+                r
+            }
+        });
+        let u = l.upgradable_read().unwrap();
+        let before = *u;
+        let mut w = u.upgrade();
+        *w += 1;
+        let r = w.downgrade();
+        assert_eq!(*r, before + 1);
+        drop(r);
+        assert!(t.join().unwrap() <= 1);
+        assert_eq!(*l.read().unwrap(), 2);
+    });
+}
@@ -0,0 +1,192 @@
+use crate::sync::atomic::{fence, AtomicU32};
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use crate::futex::{wait, wake_all};
+
+/// A counting semaphore: a number of permits that threads can take, waiting
+/// until enough are available, and give back by dropping their `SemaphorePermit`.
+pub struct Semaphore {
+    /// The number of available permits. Waiting threads wait on this.
+    permits: AtomicU32,
+    /// The number of waiting threads, so we can skip the wake syscall if there are none.
+    num_waiters: AtomicU32,
+}
+
+/// Releases its permits when dropped.
+#[must_use = "the permits are released right away if the permit is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: u32,
+}
+
+impl Semaphore {
+    crate::sync::const_fn! {
+        pub const fn new(permits: u32) -> Self {
+            Self {
+                permits: AtomicU32::new(permits),
+                num_waiters: AtomicU32::new(0),
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> u32 {
+        self.permits.load(Relaxed)
+    }
+
+    /// Waits until a permit is available, and takes it.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `n` permits are available, and takes them all at once.
+    ///
+    /// Never returns if the semaphore never has that many permits.
+    pub fn acquire_many(&self, n: u32) -> SemaphorePermit<'_> {
+        loop {
+            if let Some(permit) = self.try_acquire_many(n) {
+                return permit;
+            }
+            self.num_waiters.fetch_add(1, Relaxed);
+            // Pairs with the fence in `add_permits`: either it sees that we're
+            // waiting, or we see the permits it added (and don't go to sleep).
+            fence(SeqCst);
+            let p = self.permits.load(Relaxed);
+            if p < n {
+                wait(&self.permits, p);
+            }
+            self.num_waiters.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Takes a permit if one is available right now.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `n` permits if that many are available right now.
+    pub fn try_acquire_many(&self, n: u32) -> Option<SemaphorePermit<'_>> {
+        let mut p = self.permits.load(Relaxed);
+        loop {
+            if p < n {
+                return None;
+            }
+            // Acquire, to see everything done by those that released the permits.
+            match self.permits.compare_exchange_weak(p, p - n, Acquire, Relaxed) {
+                Ok(_) => return Some(SemaphorePermit { semaphore: self, permits: n }),
+                Err(e) => p = e,
+            }
+        }
+    }
+
+    /// Adds `n` permits, such as ones that were `forget`-ed earlier.
+    pub fn add_permits(&self, n: u32) {
+        if n == 0 {
+            return;
+        }
+        // Release, for `try_acquire_many`.
+        self.permits.fetch_add(n, Release);
+        // See `acquire_many`.
+        fence(SeqCst);
+        if self.num_waiters.load(Relaxed) > 0 {
+            // Not just one: the first one we'd wake might be waiting for more
+            // permits than there are, while another waiting thread needs fewer.
+            wake_all(&self.permits);
+        }
+    }
+}
+
+impl SemaphorePermit<'_> {
+    pub fn num_permits(&self) -> u32 {
+        self.permits
+    }
+
+    /// Keeps the permits, without ever giving them back to the semaphore.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+#[test]
+fn main() {
+    let semaphore = Semaphore::new(3);
+    let a = semaphore.acquire();
+    let b = semaphore.acquire_many(2);
+    assert_eq!(b.num_permits(), 2);
+    assert_eq!(semaphore.available_permits(), 0);
+    assert!(semaphore.try_acquire().is_none());
+    drop(b);
+    assert!(semaphore.try_acquire_many(3).is_none());
+    let c = semaphore.try_acquire_many(2).unwrap();
+    drop(a);
+    c.forget();
+    assert_eq!(semaphore.available_permits(), 1);
+    semaphore.add_permits(2);
+    assert_eq!(semaphore.acquire_many(3).num_permits(), 3);
+    assert_eq!(semaphore.available_permits(), 3);
+}
+
+/// Ten people, but only four tellers: nobody waits forever,
+/// and no more than four people are served at once.
+#[test]
+fn tellers() {
+    use std::thread;
+    use std::time::Duration;
+
+    let tellers = Semaphore::new(4);
+    let being_served = AtomicU32::new(0);
+    let max_served = AtomicU32::new(0);
+    let done = AtomicU32::new(0);
+    thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| {
+                let permit = tellers.acquire();
+                let n = being_served.fetch_add(1, Relaxed) + 1;
+                max_served.fetch_max(n, Relaxed);
+                thread::sleep(Duration::from_millis(10));
+                being_served.fetch_sub(1, Relaxed);
+                done.fetch_add(1, Relaxed);
+                drop(permit);
+            });
+        }
+        // Someone who needs two tellers at once.
+        s.spawn(|| {
+            let permit = tellers.acquire_many(2);
+            let n = being_served.fetch_add(2, Relaxed) + 2;
+            max_served.fetch_max(n, Relaxed);
+            thread::sleep(Duration::from_millis(10));
+            being_served.fetch_sub(2, Relaxed);
+            drop(permit);
+        });
+    });
+    assert_eq!(done.into_inner(), 10);
+    assert!(max_served.into_inner() <= 4);
+    assert_eq!(tellers.available_permits(), 4);
+    assert_eq!(tellers.num_waiters.load(Relaxed), 0);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::atomic::AtomicU32;
+    use crate::sync::{thread, Arc};
+    crate::sync::model(|| {
+        let s = Arc::new((Semaphore::new(0), AtomicU32::new(0)));
+        let t = thread::spawn({
+            let s = s.clone();
+            move || {
+                s.1.store(1, Relaxed);
+                s.0.add_permits(1);
+            }
+        });
+        let permit = s.0.acquire();
+        // Relaxed, since the permit comes with everything that happened before it was added.
+        assert_eq!(s.1.load(Relaxed), 1);
+        drop(permit);
+        t.join().unwrap();
+    });
+}
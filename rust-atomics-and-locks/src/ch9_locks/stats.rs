@@ -0,0 +1,344 @@
+//! Contention statistics for `mutex_3::Mutex` and `rwlock_3::RwLock`,
+//! enabled by the `lock-stats` feature.
+//!
+//! Every lock counts how often it was locked, how often that meant waiting, and how long
+//! it was held and waited for. A lock registers itself here the first time it's locked,
+//! so `report` can list all locks that are still around, busiest first.
+//!
+//! Without the feature, everything that records statistics is an empty
+//! function on a zero-sized type, so nothing is left of it after inlining.
+
+#[cfg(feature = "lock-stats")]
+pub use enabled::*;
+
+#[cfg(feature = "lock-stats")]
+pub(crate) use enabled::{Contention, HoldTimer, LockStats};
+
+#[cfg(not(feature = "lock-stats"))]
+pub(crate) use disabled::{Contention, HoldTimer, LockStats};
+
+#[cfg(feature = "lock-stats")]
+mod enabled {
+    use std::any::type_name;
+    use std::fmt;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::{Arc, Mutex, Weak};
+    use std::time::{Duration, Instant};
+    use crate::ch9_locks::once_lock::OnceLock;
+
+    /// Histogram bucket `i` counts durations below 2^i nanoseconds (and not in an earlier bucket).
+    /// The last one counts everything from about 4.5 minutes.
+    const BUCKETS: usize = 40;
+
+    pub(crate) struct LockStats {
+        /// Allocated (and registered) the first time the lock is locked,
+        /// since `new` is a const fn.
+        counters: OnceLock<Arc<Counters>>,
+    }
+
+    struct Counters {
+        name: &'static str,
+        address: usize,
+        acquisitions: AtomicU64,
+        contended: AtomicU64,
+        spins: AtomicU64,
+        waits: AtomicU64,
+        hold_time: Histogram,
+        wait_time: Histogram,
+    }
+
+    struct Histogram {
+        buckets: [AtomicU64; BUCKETS],
+    }
+
+    /// Collected while waiting for a lock.
+    pub(crate) struct Contention {
+        /// `None` if we didn't have to wait.
+        start: Option<Instant>,
+        spins: u64,
+        waits: u64,
+    }
+
+    /// Stored in a lock guard.
+    #[derive(Clone, Copy)]
+    pub(crate) struct HoldTimer(Instant);
+
+    /// The statistics of one lock, from `Mutex::stats`, `RwLock::stats` or `all`.
+    #[derive(Clone, Debug)]
+    pub struct StatsSnapshot {
+        /// The type of the lock.
+        pub name: &'static str,
+        pub address: usize,
+        /// The number of times it was locked (for reading or writing).
+        pub acquisitions: u64,
+        /// The number of times it was already locked, so we had to spin or wait.
+        pub contended: u64,
+        /// The total number of iterations spent spinning.
+        pub spins: u64,
+        /// The total number of times a thread went to sleep waiting for it.
+        pub waits: u64,
+        pub hold_time: HistogramSnapshot,
+        /// Only counts contended acquisitions.
+        pub wait_time: HistogramSnapshot,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct HistogramSnapshot {
+        buckets: [u64; BUCKETS],
+    }
+
+    static REGISTRY: Mutex<Vec<Weak<Counters>>> = Mutex::new(Vec::new());
+
+    impl LockStats {
+        crate::sync::const_fn! {
+            pub const fn new() -> Self {
+                Self { counters: OnceLock::new() }
+            }
+        }
+
+        /// `L` is the type of the lock, for its name.
+        fn counters<L: ?Sized>(&self) -> &Counters {
+            self.counters.get_or_init(|| {
+                let counters = Arc::new(Counters {
+                    name: type_name::<L>(),
+                    address: self as *const Self as usize,
+                    acquisitions: AtomicU64::new(0),
+                    contended: AtomicU64::new(0),
+                    spins: AtomicU64::new(0),
+                    waits: AtomicU64::new(0),
+                    hold_time: Histogram::new(),
+                    wait_time: Histogram::new(),
+                });
+                let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+                registry.retain(|c| c.strong_count() > 0);
+                registry.push(Arc::downgrade(&counters));
+                counters
+            })
+        }
+
+        #[inline]
+        pub fn acquired<L: ?Sized>(&self, contention: Contention) -> HoldTimer {
+            let now = Instant::now();
+            let c = self.counters::<L>();
+            c.acquisitions.fetch_add(1, Relaxed);
+            if let Some(start) = contention.start {
+                c.contended.fetch_add(1, Relaxed);
+                c.spins.fetch_add(contention.spins, Relaxed);
+                c.waits.fetch_add(contention.waits, Relaxed);
+                c.wait_time.record(now - start);
+            }
+            HoldTimer(now)
+        }
+
+        #[inline]
+        pub fn released(&self, timer: &HoldTimer) {
+            // Always initialized, since we got a HoldTimer from `acquired`.
+            if let Some(c) = self.counters.get() {
+                c.hold_time.record(timer.0.elapsed());
+            }
+        }
+
+        /// `L` is the type of the lock, for its name.
+        pub fn snapshot<L: ?Sized>(&self) -> StatsSnapshot {
+            self.counters::<L>().snapshot()
+        }
+    }
+
+    impl Counters {
+        fn snapshot(&self) -> StatsSnapshot {
+            StatsSnapshot {
+                name: self.name,
+                address: self.address,
+                acquisitions: self.acquisitions.load(Relaxed),
+                contended: self.contended.load(Relaxed),
+                spins: self.spins.load(Relaxed),
+                waits: self.waits.load(Relaxed),
+                hold_time: self.hold_time.snapshot(),
+                wait_time: self.wait_time.snapshot(),
+            }
+        }
+    }
+
+    impl Histogram {
+        fn new() -> Self {
+            Self { buckets: std::array::from_fn(|_| AtomicU64::new(0)) }
+        }
+
+        fn record(&self, d: Duration) {
+            let nanos = u64::try_from(d.as_nanos()).unwrap_or(u64::MAX);
+            let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+            self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Relaxed);
+        }
+
+        fn snapshot(&self) -> HistogramSnapshot {
+            HistogramSnapshot { buckets: std::array::from_fn(|i| self.buckets[i].load(Relaxed)) }
+        }
+    }
+
+    impl Contention {
+        pub const fn none() -> Self {
+            Self { start: None, spins: 0, waits: 0 }
+        }
+
+        /// Starts the wait timer, unless it's running already.
+        pub fn start(&mut self) -> &mut Self {
+            self.start.get_or_insert_with(Instant::now);
+            self
+        }
+
+        #[inline]
+        pub fn spin(&mut self) {
+            self.spins += 1;
+        }
+
+        #[inline]
+        pub fn wait(&mut self) {
+            self.waits += 1;
+        }
+    }
+
+    impl HistogramSnapshot {
+        pub fn count(&self) -> u64 {
+            self.buckets.iter().sum()
+        }
+
+        /// An upper bound for the duration below which the fraction `p` of all samples lie,
+        /// accurate to a factor two. Zero if there are no samples.
+        pub fn percentile(&self, p: f64) -> Duration {
+            let target = (self.count() as f64 * p).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (i, &n) in self.buckets.iter().enumerate() {
+                seen += n;
+                if seen >= target && n > 0 {
+                    return Duration::from_nanos(1 << i);
+                }
+            }
+            Duration::ZERO
+        }
+    }
+
+    impl fmt::Display for StatsSnapshot {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "{} at {:#x}", self.name, self.address)?;
+            writeln!(
+                f,
+                "  {} acquisitions, {} contended ({:.1}%), {} spins, {} waits",
+                self.acquisitions,
+                self.contended,
+                self.contended as f64 * 100.0 / self.acquisitions.max(1) as f64,
+                self.spins,
+                self.waits,
+            )?;
+            for (what, h) in [("held", &self.hold_time), ("waited", &self.wait_time)] {
+                writeln!(
+                    f,
+                    "  {what}: p50 < {:?}, p99 < {:?}, max < {:?}",
+                    h.percentile(0.5),
+                    h.percentile(0.99),
+                    h.percentile(1.0),
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// The statistics of all locks that have been locked at least once and still exist.
+    pub fn all() -> Vec<StatsSnapshot> {
+        let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        registry.iter().filter_map(Weak::upgrade).map(|c| c.snapshot()).collect()
+    }
+
+    /// A human readable report of `all` locks, the most contended ones first.
+    pub fn report() -> String {
+        let mut all = all();
+        all.sort_by_key(|s| std::cmp::Reverse((s.contended, s.acquisitions)));
+        all.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+#[cfg(not(feature = "lock-stats"))]
+mod disabled {
+    pub(crate) struct LockStats;
+
+    pub(crate) struct Contention;
+
+    #[derive(Clone, Copy)]
+    pub(crate) struct HoldTimer;
+
+    impl LockStats {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        /// Takes the same type parameter as the real one.
+        #[allow(clippy::extra_unused_type_parameters)]
+        #[inline(always)]
+        pub fn acquired<L: ?Sized>(&self, _contention: Contention) -> HoldTimer {
+            HoldTimer
+        }
+
+        #[inline(always)]
+        pub fn released(&self, _timer: &HoldTimer) {}
+    }
+
+    impl Contention {
+        pub const fn none() -> Self {
+            Self
+        }
+
+        #[inline(always)]
+        pub fn start(&mut self) -> &mut Self {
+            self
+        }
+
+        #[inline(always)]
+        pub fn spin(&mut self) {}
+
+        #[inline(always)]
+        pub fn wait(&mut self) {}
+    }
+}
+
+#[cfg(feature = "lock-stats")]
+#[test]
+fn main() {
+    use super::mutex_3::Mutex;
+    use super::rwlock_3::RwLock;
+    use std::thread;
+    use std::time::Duration;
+
+    let m = Mutex::new(0);
+    let l = RwLock::new(0);
+    assert_eq!(m.stats().acquisitions, 0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    let mut g = m.lock().unwrap();
+                    thread::sleep(Duration::from_micros(100));
+                    *g += 1;
+                    drop(g);
+                    *l.write().unwrap() += 1;
+                    drop(l.read().unwrap());
+                }
+            });
+        }
+    });
+    let stats = m.stats();
+    assert_eq!(stats.acquisitions, 400);
+    assert!(stats.contended > 0);
+    assert!(stats.waits > 0);
+    assert_eq!(stats.hold_time.count(), 400);
+    assert_eq!(stats.wait_time.count(), stats.contended);
+    assert!(stats.hold_time.percentile(0.5) >= Duration::from_micros(100));
+    assert_eq!(l.stats().acquisitions, 800);
+    assert_eq!(l.stats().hold_time.count(), 800);
+
+    let report = report();
+    assert!(report.contains(stats.name));
+    assert!(report.contains("400 acquisitions"));
+    drop(m);
+    // Gone from the registry once dropped.
+    assert!(all().iter().all(|s| s.address != stats.address));
+}
@@ -0,0 +1,249 @@
+//! How the locks in this chapter wait for each other.
+//!
+//! `mutex_3` used to hardcode "spin 100 times, then wait on the futex".
+//! Every lock now takes a `WaitStrategy` instead, with that behaviour as the default,
+//! so it can be tuned for latency (spinning) or throughput (going to sleep early).
+
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::Relaxed;
+use crate::sync::{hint, thread};
+use std::time::Instant;
+use crate::futex;
+
+pub trait WaitStrategy {
+    /// Whether `wait` and `wake_*` are the futex operations from `crate::futex`,
+    /// such that a thread waiting using this strategy can be woken up by another
+    /// strategy, or moved to another variable with `futex::requeue`.
+    const USES_FUTEX: bool = false;
+
+    /// Called right before a thread would start waiting.
+    /// Spins for a while, for as long as `busy` keeps returning true,
+    /// hoping the lock gets unlocked soon.
+    fn spin(&self, busy: impl FnMut() -> bool);
+
+    /// Waits until the value of `a` is (possibly) no longer `expected`.
+    ///
+    /// Might return spuriously. Returns false if it returned
+    /// because the deadline has passed.
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool;
+
+    /// Wakes up one thread waiting on `a`.
+    fn wake_one(&self, a: &AtomicU32);
+
+    /// Wakes up all threads waiting on `a`.
+    fn wake_all(&self, a: &AtomicU32);
+}
+
+fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+/// Never goes to sleep: waiting threads just keep spinning.
+///
+/// Waking up is free, since there's no syscall involved, but waiting
+/// burns CPU time, which only makes sense for very short critical sections
+/// with fewer threads than cores.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpinOnly;
+
+impl WaitStrategy for SpinOnly {
+    fn spin(&self, _busy: impl FnMut() -> bool) {
+        // Waiting is spinning already.
+    }
+
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+        while a.load(Relaxed) == expected {
+            if expired(deadline) {
+                return false;
+            }
+            hint::spin_loop();
+        }
+        true
+    }
+
+    fn wake_one(&self, _a: &AtomicU32) {}
+
+    fn wake_all(&self, _a: &AtomicU32) {}
+}
+
+/// Spins a fixed number of times, then goes to sleep using a futex.
+///
+/// This is what `mutex_3` did originally, with 100 spins.
+/// With zero spins, this is what `rwlock_3` and `condvar_2` did.
+#[derive(Clone, Copy, Debug)]
+pub struct SpinThenFutex {
+    spins: u32,
+}
+
+impl SpinThenFutex {
+    pub const fn new(spins: u32) -> Self {
+        Self { spins }
+    }
+}
+
+impl Default for SpinThenFutex {
+    fn default() -> Self {
+        Self::new(100)
+    }
+}
+
+impl WaitStrategy for SpinThenFutex {
+    const USES_FUTEX: bool = true;
+
+    fn spin(&self, mut busy: impl FnMut() -> bool) {
+        let mut spin_count = 0;
+        while spin_count < self.spins && busy() {
+            spin_count += 1;
+            hint::spin_loop();
+        }
+    }
+
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+        let timeout = match deadline {
+            None => None,
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(t) if !t.is_zero() => Some(t),
+                _ => return false,
+            },
+        };
+        futex::wait_timeout(a, expected, timeout)
+    }
+
+    fn wake_one(&self, a: &AtomicU32) {
+        futex::wake_one(a);
+    }
+
+    fn wake_all(&self, a: &AtomicU32) {
+        futex::wake_all(a);
+    }
+}
+
+/// Spins with exponentially increasing pauses, then yields to
+/// the scheduler a few times, and only then goes to sleep using a futex.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// Spin for up to 2^spin_limit iterations at once.
+    spin_limit: u32,
+    /// The number of steps (spinning or yielding) before going to sleep.
+    yield_limit: u32,
+}
+
+impl Backoff {
+    pub const fn new(spin_limit: u32, yield_limit: u32) -> Self {
+        Self { spin_limit, yield_limit }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(6, 10)
+    }
+}
+
+impl WaitStrategy for Backoff {
+    const USES_FUTEX: bool = true;
+
+    fn spin(&self, mut busy: impl FnMut() -> bool) {
+        for step in 0..self.yield_limit {
+            if !busy() {
+                return;
+            }
+            if step <= self.spin_limit {
+                for _ in 0..1u32 << step {
+                    hint::spin_loop();
+                }
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+        SpinThenFutex::new(0).wait(a, expected, deadline)
+    }
+
+    fn wake_one(&self, a: &AtomicU32) {
+        futex::wake_one(a);
+    }
+
+    fn wake_all(&self, a: &AtomicU32) {
+        futex::wake_all(a);
+    }
+}
+
+/// Never goes to sleep, but yields to the scheduler while waiting.
+///
+/// Like `SpinOnly`, waking up is free, but this behaves much
+/// better when there are more threads than cores.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Yield;
+
+impl WaitStrategy for Yield {
+    fn spin(&self, _busy: impl FnMut() -> bool) {
+        // Waiting is yielding already.
+    }
+
+    fn wait(&self, a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+        while a.load(Relaxed) == expected {
+            if expired(deadline) {
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
+    }
+
+    fn wake_one(&self, _a: &AtomicU32) {}
+
+    fn wake_all(&self, _a: &AtomicU32) {}
+}
+
+#[test]
+fn all_strategies() {
+    use super::condvar_2::Condvar;
+    use super::mutex_3::Mutex;
+    use super::rwlock_3::RwLock;
+    use std::thread;
+    use std::time::Duration;
+
+    fn check<W: WaitStrategy + Copy + Sync>(strategy: W) {
+        let m = Mutex::with_strategy(0, strategy);
+        let l = RwLock::with_strategy(0, strategy);
+        let ready = Mutex::with_strategy(false, strategy);
+        let c = Condvar::with_strategy(strategy);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *m.lock().unwrap() += 1;
+                        *l.write().unwrap() += 1;
+                        assert!(*l.read().unwrap() > 0);
+                    }
+                });
+            }
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                *ready.lock().unwrap() = true;
+                c.notify_one();
+            });
+            let mut g = ready.lock().unwrap();
+            while !*g {
+                g = c.wait(g).unwrap();
+            }
+        });
+        assert_eq!(*m.lock().unwrap(), 4000);
+        assert_eq!(*l.read().unwrap(), 4000);
+        // Timeouts work too.
+        let g = m.lock().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.lock_timeout(Duration::from_millis(1)).is_err()));
+        });
+        drop(g);
+    }
+
+    check(SpinOnly);
+    check(SpinThenFutex::default());
+    check(SpinThenFutex::new(0));
+    check(Backoff::default());
+    check(Yield);
+}
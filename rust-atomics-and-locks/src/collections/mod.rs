@@ -0,0 +1,22 @@
+//! Lock-free collections, built on `reclaim::epoch`.
+//!
+//! Popping a node and freeing it is where these usually go wrong: another thread might
+//! still be reading the node we just removed, or, if its memory got reused for a new node,
+//! it might succeed in a compare-and-exchange that should have failed (the ABA problem).
+//! Removed nodes are only freed once no thread can be looking at them anymore, and
+//! the pointers we compare-and-exchange carry a tag that changes on every update.
+
+pub mod ms_queue;
+pub mod treiber_stack;
+
+mod tagged;
+
+/// The operations all collections in this module support, from any number of threads at once.
+pub trait Collection<T>: Default + Sync {
+    fn push(&self, value: T);
+
+    /// Returns `None` if the collection is empty.
+    fn pop(&self) -> Option<T>;
+
+    fn is_empty(&self) -> bool;
+}
@@ -0,0 +1,177 @@
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::cache_padded::CachePadded;
+use crate::reclaim::epoch;
+use super::tagged::{AtomicTagged, Tagged};
+use super::Collection;
+
+/// The lock-free queue by Michael and Scott.
+///
+/// A linked list from `head` to `tail`, where `head` always points to a sentinel
+/// node: the node after it holds the first value. Pushing links a node after
+/// the last one, and then moves `tail` to it. Since those are two separate steps,
+/// `tail` can fall one node behind, in which case anyone can move it forward.
+/// Popping moves `head` to the next node, which then becomes the sentinel.
+pub struct MsQueue<T> {
+    head: CachePadded<AtomicTagged<Node<T>>>,
+    tail: CachePadded<AtomicTagged<Node<T>>>,
+}
+
+struct Node<T> {
+    /// Uninitialized in the sentinel.
+    value: MaybeUninit<T>,
+    next: AtomicTagged<Node<T>>,
+}
+
+// Safety: A node only gives access to its value to the one thread that pops it.
+unsafe impl<T: Send> Send for Node<T> {}
+
+unsafe impl<T: Send> Send for MsQueue<T> {}
+unsafe impl<T: Send> Sync for MsQueue<T> {}
+
+impl<T> MsQueue<T> {
+    pub fn new() -> Self {
+        let sentinel = Box::into_raw(Box::new(Node {
+            value: MaybeUninit::uninit(),
+            next: AtomicTagged::new(Tagged::null()),
+        }));
+        Self {
+            head: CachePadded::new(AtomicTagged::new(Tagged::new(sentinel, 0))),
+            tail: CachePadded::new(AtomicTagged::new(Tagged::new(sentinel, 0))),
+        }
+    }
+}
+
+impl<T: Send> Collection<T> for MsQueue<T> {
+    fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: MaybeUninit::new(value),
+            next: AtomicTagged::new(Tagged::null()),
+        }));
+        epoch::pin(|_guard| loop {
+            let tail = self.tail.load(Acquire);
+            // Safety: We're pinned, and `tail` never points to a popped node. (See `pop`.)
+            let next = unsafe { (*tail.ptr()).next.load(Acquire) };
+            if !next.ptr().is_null() {
+                // `tail` is behind. Help move it forward, and try again.
+                let _ = self.tail.compare_exchange(tail, tail.with_ptr(next.ptr()), Release, Relaxed);
+                continue;
+            }
+            // Release, to publish the node to the thread that pops it.
+            if unsafe { (*tail.ptr()).next.compare_exchange(next, next.with_ptr(node), Release, Relaxed) }.is_ok() {
+                // If this fails, someone else already moved it forward for us.
+                let _ = self.tail.compare_exchange(tail, tail.with_ptr(node), Release, Relaxed);
+                return;
+            }
+        })
+    }
+
+    fn pop(&self) -> Option<T> {
+        epoch::pin(|guard| loop {
+            let head = self.head.load(Acquire);
+            // Safety: We're pinned.
+            let next = unsafe { (*head.ptr()).next.load(Acquire) }.ptr();
+            if next.is_null() {
+                return None;
+            }
+            // Don't let `tail` point to the node we're about to free.
+            let tail = self.tail.load(Relaxed);
+            if tail.ptr() == head.ptr() {
+                let _ = self.tail.compare_exchange(tail, tail.with_ptr(next), Release, Relaxed);
+            }
+            if self.head.compare_exchange(head, head.with_ptr(next), Acquire, Relaxed).is_ok() {
+                // Safety: `next` is the new sentinel, and we're the
+                // only one who gets to move its value out.
+                // The old sentinel is unreachable, and it's ours to destroy. Its value
+                // is uninitialized or moved out already, so `T` needn't be `'static`.
+                unsafe {
+                    let value = (*next).value.assume_init_read();
+                    guard.defer_destroy(head.ptr());
+                    return Some(value);
+                }
+            }
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        epoch::pin(|_guard| {
+            let head = self.head.load(Acquire);
+            // Safety: We're pinned.
+            unsafe { (*head.ptr()).next.load(Relaxed).ptr().is_null() }
+        })
+    }
+}
+
+impl<T> Default for MsQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        // Safety: Nobody else can access the queue anymore, and popped nodes aren't in it.
+        let sentinel = unsafe { Box::from_raw(self.head.get_mut().ptr()) };
+        let mut p = sentinel.next.load(Relaxed).ptr();
+        while !p.is_null() {
+            let mut node = unsafe { Box::from_raw(p) };
+            unsafe { node.value.assume_init_drop() };
+            p = node.next.get_mut().ptr();
+        }
+    }
+}
+
+#[test]
+fn main() {
+    let queue = MsQueue::new();
+    assert!(queue.is_empty());
+    queue.push(1);
+    queue.push(2);
+    queue.push(3);
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.pop(), Some(2));
+    queue.push(4);
+    assert!(!queue.is_empty());
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), Some(4));
+    assert_eq!(queue.pop(), None);
+    assert!(queue.is_empty());
+    // Values left behind are dropped with the queue.
+    let strings = MsQueue::new();
+    strings.push(String::from("hello"));
+}
+
+#[test]
+fn stress() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    let per_thread = if cfg!(miri) { 100 } else { 10_000 };
+    let queue = MsQueue::new();
+    let popped = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for t in 0..4 {
+            let queue = &queue;
+            s.spawn(move || {
+                for i in 0..per_thread {
+                    queue.push((t, i));
+                }
+            });
+        }
+        for _ in 0..4 {
+            s.spawn(|| {
+                // The values from each producer come out in the order they went in.
+                let mut next = [0; 4];
+                while popped.load(Relaxed) < 4 * per_thread {
+                    if let Some((t, i)) = queue.pop() {
+                        assert!(i >= next[t]);
+                        next[t] = i + 1;
+                        popped.fetch_add(1, Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(popped.into_inner(), 4 * per_thread);
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,114 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// A pointer with a small tag in its unused lower (alignment) bits.
+pub(crate) struct Tagged<T> {
+    ptr: *mut T,
+}
+
+impl<T> Tagged<T> {
+    const MASK: usize = mem::align_of::<T>() - 1;
+
+    pub(crate) fn null() -> Self {
+        Self { ptr: ptr::null_mut() }
+    }
+
+    /// `ptr` must be aligned. Only the lower bits of `tag` are kept.
+    pub(crate) fn new(ptr: *mut T, tag: usize) -> Self {
+        debug_assert_eq!(ptr as usize & Self::MASK, 0);
+        // Through `wrapping_add` rather than casting an integer back
+        // to a pointer, so we keep the provenance of the original pointer.
+        Self { ptr: ptr.cast::<u8>().wrapping_add(tag & Self::MASK).cast() }
+    }
+
+    pub(crate) fn ptr(self) -> *mut T {
+        self.ptr.cast::<u8>().wrapping_sub(self.tag()).cast()
+    }
+
+    pub(crate) fn tag(self) -> usize {
+        self.ptr as usize & Self::MASK
+    }
+
+    /// Points to `ptr` instead, with the next tag, so
+    /// it doesn't compare equal to `self` even if `ptr` does.
+    pub(crate) fn with_ptr(self, ptr: *mut T) -> Self {
+        Self::new(ptr, self.tag().wrapping_add(1))
+    }
+}
+
+impl<T> Clone for Tagged<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Tagged<T> {}
+
+impl<T> PartialEq for Tagged<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+/// An atomic `Tagged<T>`.
+pub(crate) struct AtomicTagged<T> {
+    ptr: AtomicPtr<T>,
+    /// Not Send or Sync: that's up to the collection using it.
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> AtomicTagged<T> {
+    pub(crate) fn new(value: Tagged<T>) -> Self {
+        Self { ptr: AtomicPtr::new(value.ptr), _marker: PhantomData }
+    }
+
+    pub(crate) fn load(&self, order: Ordering) -> Tagged<T> {
+        Tagged { ptr: self.ptr.load(order) }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> Tagged<T> {
+        Tagged { ptr: *self.ptr.get_mut() }
+    }
+
+    pub(crate) fn compare_exchange(
+        &self,
+        current: Tagged<T>,
+        new: Tagged<T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Tagged<T>, Tagged<T>> {
+        self.ptr.compare_exchange(current.ptr, new.ptr, success, failure)
+            .map(|ptr| Tagged { ptr })
+            .map_err(|ptr| Tagged { ptr })
+    }
+
+    pub(crate) fn compare_exchange_weak(
+        &self,
+        current: Tagged<T>,
+        new: Tagged<T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Tagged<T>, Tagged<T>> {
+        self.ptr.compare_exchange_weak(current.ptr, new.ptr, success, failure)
+            .map(|ptr| Tagged { ptr })
+            .map_err(|ptr| Tagged { ptr })
+    }
+}
+
+#[test]
+fn main() {
+    let mut x = 0u64;
+    let p = &mut x as *mut u64;
+    let a = Tagged::new(p, 3);
+    assert_eq!(a.ptr(), p);
+    assert_eq!(a.tag(), 3);
+    let b = a.with_ptr(p);
+    assert_eq!(b.ptr(), p);
+    assert_eq!(b.tag(), 4);
+    assert!(a != b);
+    // The tag wraps around within the alignment bits.
+    assert_eq!(Tagged::new(p, 7).with_ptr(p).tag(), 0);
+    assert!(Tagged::<u64>::null().ptr().is_null());
+}
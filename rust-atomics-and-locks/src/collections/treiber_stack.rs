@@ -0,0 +1,156 @@
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::reclaim::epoch;
+use super::tagged::{AtomicTagged, Tagged};
+use super::Collection;
+
+/// A lock-free stack: a linked list of which we only ever
+/// replace the head, with a compare-and-exchange loop.
+pub struct TreiberStack<T> {
+    head: AtomicTagged<Node<T>>,
+}
+
+struct Node<T> {
+    /// Moved out by whoever pops this node, so not dropped with it.
+    value: ManuallyDrop<T>,
+    /// Never changes after this node is pushed.
+    next: *mut Node<T>,
+}
+
+// Safety: A node only gives access to its value to the one thread that pops it.
+unsafe impl<T: Send> Send for Node<T> {}
+
+unsafe impl<T: Send> Send for TreiberStack<T> {}
+unsafe impl<T: Send> Sync for TreiberStack<T> {}
+
+impl<T> TreiberStack<T> {
+    pub fn new() -> Self {
+        Self { head: AtomicTagged::new(Tagged::null()) }
+    }
+}
+
+impl<T: Send> Collection<T> for TreiberStack<T> {
+    fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+        // No need to pin: we never look at the other nodes.
+        let mut head = self.head.load(Relaxed);
+        loop {
+            // Safety: Nobody else has seen this node yet.
+            unsafe { (*node).next = head.ptr() };
+            // Release, to publish the node to the thread that pops it.
+            match self.head.compare_exchange_weak(head, head.with_ptr(node), Release, Relaxed) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        epoch::pin(|guard| {
+            let mut head = self.head.load(Acquire);
+            loop {
+                let node = head.ptr();
+                if node.is_null() {
+                    return None;
+                }
+                // Safety: We're pinned, so the node isn't freed even if it's popped right now.
+                let next = unsafe { (*node).next };
+                // If `head` was popped and pushed again in the meantime, `next` might be outdated,
+                // but then the tag has changed too, so this fails.
+                match self.head.compare_exchange_weak(head, head.with_ptr(next), Acquire, Acquire) {
+                    Ok(_) => {
+                        // Safety: We popped the node, so the value is ours,
+                        // and nobody else is going to destroy the node.
+                        // Dropping it doesn't drop the value, so `T` needn't be `'static`.
+                        unsafe {
+                            let value = ptr::read(&*(*node).value);
+                            guard.defer_destroy(node);
+                            return Some(value);
+                        }
+                    }
+                    Err(h) => head = h,
+                }
+            }
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).ptr().is_null()
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        let mut p = self.head.get_mut().ptr();
+        while !p.is_null() {
+            // Safety: Nobody else can access the stack anymore, and popped nodes aren't in it.
+            let mut node = unsafe { Box::from_raw(p) };
+            unsafe { ManuallyDrop::drop(&mut node.value) };
+            p = node.next;
+        }
+    }
+}
+
+#[test]
+fn main() {
+    let stack = TreiberStack::new();
+    assert!(stack.is_empty());
+    stack.push(1);
+    stack.push(2);
+    stack.push(3);
+    assert_eq!(stack.pop(), Some(3));
+    assert_eq!(stack.pop(), Some(2));
+    stack.push(4);
+    assert_eq!(stack.pop(), Some(4));
+    assert_eq!(stack.pop(), Some(1));
+    assert_eq!(stack.pop(), None);
+    assert!(stack.is_empty());
+    // Values left behind are dropped with the stack.
+    let strings = TreiberStack::new();
+    strings.push(String::from("hello"));
+}
+
+#[test]
+fn stress() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    let per_thread = if cfg!(miri) { 100 } else { 10_000 };
+    let stack = TreiberStack::new();
+    let popped = AtomicUsize::new(0);
+    let sum = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for t in 0..4 {
+            let stack = &stack;
+            s.spawn(move || {
+                for i in 0..per_thread {
+                    stack.push(t * per_thread + i);
+                }
+            });
+        }
+        for _ in 0..4 {
+            s.spawn(|| {
+                while popped.load(Relaxed) < 4 * per_thread {
+                    if let Some(n) = stack.pop() {
+                        popped.fetch_add(1, Relaxed);
+                        sum.fetch_add(n, Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    // Every value was popped exactly once.
+    let n = 4 * per_thread;
+    assert_eq!(sum.into_inner(), n * (n - 1) / 2);
+    assert!(stack.is_empty());
+}
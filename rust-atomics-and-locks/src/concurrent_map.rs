@@ -0,0 +1,218 @@
+//! The part of papaya's API that `ShardedMap` has too, as traits, so the
+//! same code can run on either map. See `papaya-test` for an example.
+//!
+//! With the `papaya` feature, `papaya::HashMap` implements them too. For another map,
+//! implement `ConcurrentMap` for it, and `PinnedMap` for whatever its `pin()` returns.
+//! (Or for a wrapper around it, if both the map and the traits are from other crates.)
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+use crate::sharded_map::{self, ShardedMap};
+
+/// A concurrent map, used through `pin()`.
+pub trait ConcurrentMap<K, V> {
+    type Pinned<'a>: PinnedMap<K, V>
+    where
+        Self: 'a;
+
+    fn pin(&self) -> Self::Pinned<'_>;
+}
+
+/// A pinned `ConcurrentMap`, for as long as it's used on one thread.
+pub trait PinnedMap<K, V> {
+    /// A reference to a value in the map.
+    ///
+    /// Might keep (part of) the map locked, so using the
+    /// map again while holding one might deadlock.
+    type Ref<'a>: Deref<Target = V>
+    where
+        Self: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<Self::Ref<'_>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Returns the old value, if there was one.
+    fn insert(&self, key: K, value: V) -> Option<V>;
+
+    fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Replaces the value with `update` of it, or inserts `insert()` if there's none.
+    /// Returns the new value. `update` might be called more than once.
+    fn update_or_insert_with(&self, key: K, update: impl Fn(&V) -> V, insert: impl FnOnce() -> V) -> Self::Ref<'_>;
+
+    /// `update_or_insert_with`, with a value to insert that's already there.
+    fn update_or_insert(&self, key: K, update: impl Fn(&V) -> V, value: V) -> Self::Ref<'_> {
+        self.update_or_insert_with(key, update, || value)
+    }
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls `f` for every entry. Not a snapshot: entries inserted or
+    /// removed in the meantime might or might not be seen.
+    fn for_each(&self, f: impl FnMut(&K, &V));
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ConcurrentMap<K, V> for ShardedMap<K, V, S> {
+    type Pinned<'a> = sharded_map::Pinned<'a, K, V, S> where Self: 'a;
+
+    fn pin(&self) -> Self::Pinned<'_> {
+        ShardedMap::pin(self)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> PinnedMap<K, V> for sharded_map::Pinned<'_, K, V, S> {
+    type Ref<'a> = sharded_map::Ref<'a, K, V, S> where Self: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<Self::Ref<'_>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ShardedMap::get(self, key)
+    }
+
+    fn insert(&self, key: K, value: V) -> Option<V> {
+        ShardedMap::insert(self, key, value)
+    }
+
+    fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ShardedMap::remove(self, key)
+    }
+
+    fn update_or_insert_with(&self, key: K, update: impl Fn(&V) -> V, insert: impl FnOnce() -> V) -> Self::Ref<'_> {
+        ShardedMap::update_or_insert_with(self, key, update, insert)
+    }
+
+    fn len(&self) -> usize {
+        ShardedMap::len(self)
+    }
+
+    fn for_each(&self, f: impl FnMut(&K, &V)) {
+        ShardedMap::for_each(self, f)
+    }
+}
+
+#[cfg(feature = "papaya")]
+impl<K: Hash + Eq, V: Clone, S: BuildHasher> ConcurrentMap<K, V> for papaya::HashMap<K, V, S> {
+    type Pinned<'a> = papaya::HashMapRef<'a, K, V, S, papaya::LocalGuard<'a>> where Self: 'a;
+
+    fn pin(&self) -> Self::Pinned<'_> {
+        papaya::HashMap::pin(self)
+    }
+}
+
+/// Values that are replaced or removed are only freed once nobody can be using them anymore,
+/// so papaya returns a reference to the old value. We return a clone of it instead.
+#[cfg(feature = "papaya")]
+impl<K, V, S, G> PinnedMap<K, V> for papaya::HashMapRef<'_, K, V, S, G>
+where
+    K: Hash + Eq,
+    V: Clone,
+    S: BuildHasher,
+    G: papaya::Guard,
+{
+    type Ref<'a> = &'a V where Self: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        papaya::HashMapRef::get(self, key)
+    }
+
+    fn insert(&self, key: K, value: V) -> Option<V> {
+        papaya::HashMapRef::insert(self, key, value).cloned()
+    }
+
+    fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        papaya::HashMapRef::remove(self, key).cloned()
+    }
+
+    fn update_or_insert_with(&self, key: K, update: impl Fn(&V) -> V, insert: impl FnOnce() -> V) -> &V {
+        papaya::HashMapRef::update_or_insert_with(self, key, update, insert)
+    }
+
+    fn len(&self) -> usize {
+        papaya::HashMapRef::len(self)
+    }
+
+    fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        for (k, v) in self.iter() {
+            f(k, v);
+        }
+    }
+}
+
+/// Used the same way as `papaya-test` uses its maps.
+#[cfg(test)]
+fn check<M, N>()
+where
+    M: ConcurrentMap<char, i32> + Default + Sync,
+    N: ConcurrentMap<&'static str, i32> + Default,
+{
+    let map = M::default();
+    let map = map.pin();
+    assert_eq!(map.insert('A', 1), None);
+    assert_eq!(map.get(&'A').as_deref(), Some(&1));
+    assert_eq!(map.len(), 1);
+
+    let map = M::default();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let map = map.pin();
+            for i in 'A'..='Z' {
+                map.insert(i, 1);
+            }
+        });
+        s.spawn(|| {
+            map.pin().for_each(|key, value| {
+                assert!(key.is_ascii_uppercase() && *value == 1);
+            });
+        });
+        s.spawn(|| {
+            let map = map.pin();
+            for i in 'A'..='Z' {
+                map.remove(&i);
+            }
+        });
+    });
+
+    let map = N::default();
+    let map = map.pin();
+    map.insert("poney", 42);
+    assert_eq!(*map.update_or_insert("poney", |e| e + 1, 42), 43);
+    assert_eq!(map.remove("poney"), Some(43));
+    assert!(map.is_empty());
+    assert_eq!(*map.update_or_insert_with("a", |i| i + 1, || 0), 0);
+    assert_eq!(*map.update_or_insert_with("a", |i| i + 1, || 0), 1);
+}
+
+#[test]
+fn sharded_map() {
+    check::<ShardedMap<_, _>, ShardedMap<_, _>>();
+}
+
+#[cfg(feature = "papaya")]
+#[test]
+fn papaya() {
+    check::<papaya::HashMap<_, _>, papaya::HashMap<_, _>>();
+}
@@ -0,0 +1,67 @@
+//! The futex syscalls. Refer to the futex (2) man page for their signatures.
+
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+/// Returns false if it returned because the timeout elapsed.
+pub fn wait(a: &AtomicU32, expected: u32, bitset: u32, timeout: Option<Duration>) -> bool {
+    // Unlike FUTEX_WAIT, FUTEX_WAIT_BITSET takes an absolute timeout, measured against
+    // CLOCK_MONOTONIC. (With a bitset that matches everything, they're otherwise the same.)
+    let deadline = timeout.map(|t| {
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let nsec = now.tv_nsec as u32 + t.subsec_nanos();
+        let sec = t.as_secs()
+            .try_into()
+            .ok()
+            .and_then(|s: libc::time_t| now.tv_sec.checked_add(s))
+            .and_then(|s| s.checked_add((nsec / 1_000_000_000) as _))
+            .unwrap_or(libc::time_t::MAX);
+        libc::timespec { tv_sec: sec, tv_nsec: (nsec % 1_000_000_000) as _ }
+    });
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            deadline.as_ref().map_or(std::ptr::null(), |t| t as *const libc::timespec),
+            std::ptr::null::<u32>(), // Unused.
+            bitset,
+        )
+    };
+    !(r < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+}
+
+pub fn wake(a: &AtomicU32, count: u32, bitset: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAKE_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            to_int(count),
+            std::ptr::null::<libc::timespec>(), // Unused.
+            std::ptr::null::<u32>(), // Unused.
+            bitset,
+        );
+    }
+}
+
+pub fn requeue(from: &AtomicU32, to: &AtomicU32, wake: u32, requeue: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            from as *const AtomicU32,
+            libc::FUTEX_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            to_int(wake),
+            // Not a timeout: the maximum number of threads to requeue.
+            to_int(requeue) as libc::c_long,
+            to as *const AtomicU32,
+        );
+    }
+}
+
+/// The syscall takes signed counts.
+fn to_int(count: u32) -> libc::c_int {
+    count.min(libc::c_int::MAX as u32) as libc::c_int
+}
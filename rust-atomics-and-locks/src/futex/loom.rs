@@ -0,0 +1,89 @@
+//! Futexes for loom models: the same wait queue as `parking`, but only one,
+//! protected by a loom `Mutex`, so loom explores every way waiting and waking
+//! can interleave with everything else.
+//!
+//! Waiting threads block on a loom `Condvar`, not with thread parking:
+//! loom lets an unpark end any kind of blocking, such as a `join`,
+//! so an unpark that arrives late would break the model.
+//!
+//! Loom has no notion of time. A wait with a timeout returns right away, as
+//! if the timeout elapsed immediately, which is always something it may do.
+
+use std::time::Duration;
+use loom::sync::{Condvar, Mutex};
+use crate::sync::atomic::AtomicU32;
+use crate::sync::atomic::Ordering::Relaxed;
+
+struct Waiter {
+    id: u64,
+    address: usize,
+    bitset: u32,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_id: u64,
+    /// A waiter is woken by removing it from here.
+    waiters: Vec<Waiter>,
+}
+
+loom::lazy_static! {
+    /// Like a futex bucket in the kernel, this lock makes checking the
+    /// value and starting to wait one atomic step, as far as wakers are concerned.
+    static ref QUEUE: Mutex<Queue> = Mutex::new(Queue::default());
+    static ref WOKEN: Condvar = Condvar::new();
+}
+
+fn address(a: &AtomicU32) -> usize {
+    a as *const AtomicU32 as usize
+}
+
+pub fn wait(a: &AtomicU32, expected: u32, bitset: u32, timeout: Option<Duration>) -> bool {
+    let mut queue = QUEUE.lock().unwrap();
+    if a.load(Relaxed) != expected {
+        return true;
+    }
+    if timeout.is_some() {
+        return false;
+    }
+    let id = queue.next_id;
+    queue.next_id += 1;
+    queue.waiters.push(Waiter { id, address: address(a), bitset });
+    // Whatever the waker did before waking us happens before we return,
+    // since it held the lock when it did.
+    while queue.waiters.iter().any(|w| w.id == id) {
+        queue = WOKEN.wait(queue).unwrap();
+    }
+    true
+}
+
+pub fn wake(a: &AtomicU32, count: u32, bitset: u32) {
+    let address = address(a);
+    let mut queue = QUEUE.lock().unwrap();
+    if remove(&mut queue.waiters, count, |w| w.address == address && w.bitset & bitset != 0) {
+        WOKEN.notify_all();
+    }
+}
+
+pub fn requeue(from: &AtomicU32, to: &AtomicU32, wake: u32, requeue: u32) {
+    let (from, to) = (address(from), address(to));
+    let mut queue = QUEUE.lock().unwrap();
+    let woken = remove(&mut queue.waiters, wake, |w| w.address == from);
+    for w in queue.waiters.iter_mut().filter(|w| w.address == from).take(requeue as usize) {
+        w.address = to;
+    }
+    if woken {
+        WOKEN.notify_all();
+    }
+}
+
+/// Removes the first `count` waiters that match. Returns whether there were any.
+fn remove(waiters: &mut Vec<Waiter>, count: u32, mut matches: impl FnMut(&Waiter) -> bool) -> bool {
+    let mut removed = 0;
+    waiters.retain(|w| {
+        let remove = removed < count && matches(w);
+        removed += remove as u32;
+        !remove
+    });
+    removed > 0
+}
@@ -0,0 +1,162 @@
+//! Waiting on and waking up atomic variables, like a Linux futex.
+//!
+//! On Linux, these are the futex syscalls from `examples/ch8-01-futex.rs`.
+//! Elsewhere, or with the `futex-fallback` feature enabled, they are emulated
+//! with a global table of wait queues, the way `parking_lot` does it, using nothing
+//! but thread parking. Enabling the feature on Linux allows testing that emulation,
+//! and everything built on top of it, on the same machine.
+//!
+//! Every wait might return spuriously, so always check the value again afterwards.
+//!
+//! In a loom build, they're emulated with a single loom `Mutex` and `Condvar` instead.
+
+use std::time::Duration;
+use crate::sync::atomic::AtomicU32;
+
+#[cfg(all(target_os = "linux", not(feature = "futex-fallback"), not(loom)))]
+mod linux;
+#[cfg(all(target_os = "linux", not(feature = "futex-fallback"), not(loom)))]
+use linux as imp;
+
+#[cfg(all(any(not(target_os = "linux"), feature = "futex-fallback"), not(loom)))]
+mod parking;
+#[cfg(all(any(not(target_os = "linux"), feature = "futex-fallback"), not(loom)))]
+use parking as imp;
+
+#[cfg(loom)]
+mod loom;
+#[cfg(loom)]
+use self::loom as imp;
+
+/// Matches every bitset, for `wait_bitset` and `wake_bitset`.
+pub const BITSET_MATCH_ANY: u32 = u32::MAX;
+
+/// Waits until the value of `a` is no longer `expected`.
+pub fn wait(a: &AtomicU32, expected: u32) {
+    imp::wait(a, expected, BITSET_MATCH_ANY, None);
+}
+
+/// Waits until the value of `a` is no longer `expected`, or until `timeout` has passed.
+///
+/// Returns `false` if it returned because the timeout elapsed.
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    imp::wait(a, expected, BITSET_MATCH_ANY, timeout)
+}
+
+/// Like `wait_timeout`, but only `wake_bitset` calls with a bitset
+/// that has a bit in common with `bitset` wake us up.
+///
+/// Panics if `bitset` is zero.
+pub fn wait_bitset(a: &AtomicU32, expected: u32, bitset: u32, timeout: Option<Duration>) -> bool {
+    assert_ne!(bitset, 0, "a waiter with an empty bitset can never be woken");
+    imp::wait(a, expected, bitset, timeout)
+}
+
+/// Wakes up one thread waiting on `a`.
+pub fn wake_one(a: &AtomicU32) {
+    imp::wake(a, 1, BITSET_MATCH_ANY);
+}
+
+/// Wakes up all threads waiting on `a`.
+pub fn wake_all(a: &AtomicU32) {
+    imp::wake(a, u32::MAX, BITSET_MATCH_ANY);
+}
+
+/// Wakes up at most `count` threads waiting on `a` with a bitset that has a bit in common with `bitset`.
+///
+/// Threads that waited with `wait` or `wait_timeout` match every bitset.
+pub fn wake_bitset(a: &AtomicU32, count: u32, bitset: u32) {
+    imp::wake(a, count, bitset);
+}
+
+/// Wakes up at most `wake` threads waiting on `from`, and moves up to
+/// `requeue` of the remaining ones over to wait on `to` instead, without waking them.
+///
+/// Moved threads return from their wait once woken up through `to`, even if the value
+/// of `from` never changes, so they should check the value of `to` afterwards.
+pub fn requeue(from: &AtomicU32, to: &AtomicU32, wake: u32, requeue: u32) {
+    imp::requeue(from, to, wake, requeue);
+}
+
+#[test]
+fn main() {
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Instant;
+
+    let a = AtomicU32::new(0);
+
+    // Doesn't wait if the value is different already.
+    wait(&a, 1);
+    let start = Instant::now();
+    assert!(!wait_timeout(&a, 0, Some(Duration::from_millis(10))));
+    assert!(start.elapsed() >= Duration::from_millis(10));
+
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                while a.load(Relaxed) == 0 {
+                    wait(&a, 0);
+                }
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+        a.store(1, Relaxed);
+        wake_one(&a);
+        wake_all(&a);
+    });
+}
+
+#[test]
+fn bitset() {
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    let a = AtomicU32::new(0);
+    let woken = AtomicU32::new(0);
+    thread::scope(|s| {
+        for bit in [0b01, 0b10] {
+            let (a, woken) = (&a, &woken);
+            s.spawn(move || {
+                // Timeouts, just in case a wakeup came in before we started waiting.
+                while !wait_bitset(a, 0, bit, Some(Duration::from_millis(100))) {}
+                woken.fetch_or(bit, Relaxed);
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+        // Only the thread waiting with the second bit.
+        wake_bitset(&a, u32::MAX, 0b10);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(woken.load(Relaxed), 0b10);
+        wake_bitset(&a, u32::MAX, BITSET_MATCH_ANY);
+    });
+    assert_eq!(woken.into_inner(), 0b11);
+}
+
+#[test]
+fn requeue_to_other() {
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    let from = AtomicU32::new(0);
+    let to = AtomicU32::new(0);
+    let woken = AtomicU32::new(0);
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                wait(&from, 0);
+                woken.fetch_add(1, Relaxed);
+            });
+        }
+        thread::sleep(Duration::from_millis(50));
+        // Wake one, and move the other two.
+        requeue(&from, &to, 1, u32::MAX);
+        thread::sleep(Duration::from_millis(50));
+        // Waking `from` again has no effect anymore.
+        wake_all(&from);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(woken.load(Relaxed), 1);
+        wake_all(&to);
+    });
+    assert_eq!(woken.into_inner(), 3);
+}
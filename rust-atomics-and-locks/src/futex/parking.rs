@@ -0,0 +1,162 @@
+//! Emulated futexes, using a fixed-size hash table of wait queues.
+//!
+//! Every waiting thread is in the queue of the bucket its address hashes to.
+//! Checking the value before queueing happens with that bucket locked,
+//! and wakers lock it after changing the value, so no wakeup can get lost.
+
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+struct Waiter {
+    /// The address it's waiting on. Only changes through `requeue`,
+    /// with both the old and new bucket locked.
+    address: AtomicUsize,
+    bitset: u32,
+    thread: Thread,
+    /// Set (with the bucket locked) when removed from the queue to be woken up.
+    woken: AtomicBool,
+}
+
+type Bucket = Mutex<Vec<Arc<Waiter>>>;
+
+const BUCKET_BITS: u32 = 6;
+const NUM_BUCKETS: usize = 1 << BUCKET_BITS;
+
+static BUCKETS: [Bucket; NUM_BUCKETS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Bucket = Mutex::new(Vec::new());
+    [EMPTY; NUM_BUCKETS]
+};
+
+fn address(a: &AtomicU32) -> usize {
+    a as *const AtomicU32 as usize
+}
+
+fn bucket_index(address: usize) -> usize {
+    // Fibonacci hashing. Nearby addresses end up in different buckets.
+    address.wrapping_mul(0x9E3779B97F4A7C15u64 as usize) >> (usize::BITS - BUCKET_BITS)
+}
+
+fn lock(index: usize) -> MutexGuard<'static, Vec<Arc<Waiter>>> {
+    // We never panic while holding a bucket, but a panic in
+    // `Thread::unpark` or the allocator shouldn't break everything else.
+    BUCKETS[index].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns false if it returned because the timeout elapsed.
+pub fn wait(a: &AtomicU32, expected: u32, bitset: u32, timeout: Option<Duration>) -> bool {
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+    let waiter = {
+        let mut queue = lock(bucket_index(address(a)));
+        // Relaxed is enough: a waker that changed the value before we locked
+        // the bucket locks it after us, and then finds us in the queue.
+        if a.load(Relaxed) != expected {
+            return true;
+        }
+        let waiter = Arc::new(Waiter {
+            address: AtomicUsize::new(address(a)),
+            bitset,
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+        });
+        queue.push(waiter.clone());
+        waiter
+    };
+    loop {
+        // Acquire, so whatever the waker did before waking us happens before we return.
+        if waiter.woken.load(Acquire) {
+            return true;
+        }
+        match deadline {
+            None => thread::park(),
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(t) if !t.is_zero() => thread::park_timeout(t),
+                _ => break,
+            },
+        }
+    }
+    // Timed out. Remove ourselves from the queue, unless we've been woken up in the meantime.
+    loop {
+        let address = waiter.address.load(Relaxed);
+        let mut queue = lock(bucket_index(address));
+        if waiter.address.load(Relaxed) != address {
+            // Requeued before we got the lock.
+            continue;
+        }
+        return match queue.iter().position(|w| Arc::ptr_eq(w, &waiter)) {
+            Some(i) => {
+                queue.remove(i);
+                false
+            }
+            None => true,
+        };
+    }
+}
+
+pub fn wake(a: &AtomicU32, count: u32, bitset: u32) {
+    let address = address(a);
+    let woken = {
+        let mut queue = lock(bucket_index(address));
+        take(&mut queue, count, |w| w.address.load(Relaxed) == address && w.bitset & bitset != 0)
+    };
+    // Not while holding the lock, so the woken threads don't immediately block on it.
+    for w in woken {
+        w.thread.unpark();
+    }
+}
+
+pub fn requeue(from: &AtomicU32, to: &AtomicU32, wake: u32, requeue: u32) {
+    let (from, to) = (address(from), address(to));
+    let (i, j) = (bucket_index(from), bucket_index(to));
+    let woken = if i == j {
+        let mut queue = lock(i);
+        let woken = take(&mut queue, wake, |w| w.address.load(Relaxed) == from);
+        for w in queue.iter().filter(|w| w.address.load(Relaxed) == from).take(requeue as usize) {
+            w.address.store(to, Relaxed);
+        }
+        woken
+    } else {
+        // Always lock the lower index first, so two requeues in opposite directions can't deadlock.
+        let (mut a, mut b) = if i < j { (lock(i), lock(j)) } else {
+            let b = lock(j);
+            (lock(i), b)
+        };
+        let (from_queue, to_queue) = (&mut *a, &mut *b);
+        let woken = take(from_queue, wake, |w| w.address.load(Relaxed) == from);
+        let mut n = 0;
+        from_queue.retain(|w| {
+            if n < requeue && w.address.load(Relaxed) == from {
+                n += 1;
+                w.address.store(to, Relaxed);
+                to_queue.push(w.clone());
+                false
+            } else {
+                true
+            }
+        });
+        woken
+    };
+    for w in woken {
+        w.thread.unpark();
+    }
+}
+
+/// Removes the first `count` waiters that match, in the order they started waiting,
+/// and marks them as woken.
+fn take(queue: &mut Vec<Arc<Waiter>>, count: u32, mut matches: impl FnMut(&Waiter) -> bool) -> Vec<Arc<Waiter>> {
+    let mut woken = Vec::new();
+    queue.retain(|w| {
+        if woken.len() < count as usize && matches(w) {
+            // Release, pairing with the Acquire in `wait`.
+            w.woken.store(true, Release);
+            woken.push(w.clone());
+            false
+        } else {
+            true
+        }
+    });
+    woken
+}
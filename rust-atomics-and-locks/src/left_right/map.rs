@@ -0,0 +1,246 @@
+//! A map from keys to any number of values, with readers that never wait, like evmap.
+//!
+//! Changes only become visible to readers once the writer calls `publish`.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::{HashMap, RandomState};
+use std::hash::{BuildHasher, Hash};
+use super::{Absorb, ReadGuard};
+
+/// A change to the map.
+pub enum Operation<K, V> {
+    /// Adds a value to those of a key.
+    Insert(K, V),
+    /// Removes one value equal to this one from those of a key.
+    RemoveValue(K, V),
+    /// Removes a key, with all its values.
+    RemoveEntry(K),
+    Clear,
+}
+
+/// What both copies of the map are: a list of values for every key.
+pub type Inner<K, V, S = RandomState> = HashMap<K, Vec<V>, S>;
+
+pub struct WriteHandle<K, V, S = RandomState> {
+    handle: super::WriteHandle<Inner<K, V, S>, Operation<K, V>>,
+}
+
+/// Reads the map as it was last published.
+///
+/// Clone it for every thread that reads the map.
+pub struct ReadHandle<K, V, S = RandomState> {
+    handle: super::ReadHandle<Inner<K, V, S>>,
+}
+
+pub fn new<K, V>() -> (WriteHandle<K, V>, ReadHandle<K, V>)
+where
+    K: Hash + Eq + Clone,
+    V: Eq + Clone,
+{
+    with_hasher(RandomState::new())
+}
+
+pub fn with_hasher<K, V, S>(hasher: S) -> (WriteHandle<K, V, S>, ReadHandle<K, V, S>)
+where
+    K: Hash + Eq + Clone,
+    V: Eq + Clone,
+    S: BuildHasher + Clone,
+{
+    let (w, r) = super::new(HashMap::with_hasher(hasher));
+    (WriteHandle { handle: w }, ReadHandle { handle: r })
+}
+
+impl<K, V, S> Absorb<Operation<K, V>> for Inner<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Eq + Clone,
+    S: BuildHasher,
+{
+    fn absorb_first(&mut self, op: &Operation<K, V>) {
+        match op {
+            Operation::Insert(k, v) => self.entry(k.clone()).or_default().push(v.clone()),
+            Operation::RemoveValue(k, v) => remove_value(self, k, v),
+            Operation::RemoveEntry(k) => drop(self.remove(k)),
+            Operation::Clear => self.clear(),
+        }
+    }
+
+    fn absorb_second(&mut self, op: Operation<K, V>) {
+        match op {
+            Operation::Insert(k, v) => self.entry(k).or_default().push(v),
+            op => self.absorb_first(&op),
+        }
+    }
+}
+
+/// Removes a key altogether once its last value is removed,
+/// so a key is only in the map if it has values.
+fn remove_value<K: Hash + Eq, V: Eq, S: BuildHasher>(map: &mut Inner<K, V, S>, k: &K, v: &V) {
+    let Some(values) = map.get_mut(k) else { return };
+    if let Some(i) = values.iter().position(|x| x == v) {
+        // Not `swap_remove`: then the values would no longer be in the order they were inserted.
+        values.remove(i);
+        if values.is_empty() {
+            map.remove(k);
+        }
+    }
+}
+
+impl<K, V, S> WriteHandle<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Eq + Clone,
+    S: BuildHasher,
+{
+    /// Adds `value` to the values of `key`, even if it's there already.
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.handle.append(Operation::Insert(key, value));
+        self
+    }
+
+    /// Removes one of the values of `key` that's equal to `value`, if there is one.
+    pub fn remove_value(&mut self, key: K, value: V) -> &mut Self {
+        self.handle.append(Operation::RemoveValue(key, value));
+        self
+    }
+
+    /// Removes `key` and all its values.
+    pub fn remove_entry(&mut self, key: K) -> &mut Self {
+        self.handle.append(Operation::RemoveEntry(key));
+        self
+    }
+
+    pub fn clear(&mut self) -> &mut Self {
+        self.handle.append(Operation::Clear);
+        self
+    }
+
+    /// Makes all changes so far visible to readers, all at once.
+    /// Waits for readers that were reading the map when this was called.
+    pub fn publish(&mut self) -> &mut Self {
+        self.handle.publish();
+        self
+    }
+
+    pub fn pending(&self) -> &[Operation<K, V>] {
+        self.handle.pending()
+    }
+
+    /// The map as the writer sees it, including the changes that haven't been published yet.
+    pub fn read(&self) -> &Inner<K, V, S> {
+        self.handle.read()
+    }
+}
+
+impl<K, V, S> ReadHandle<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Calls `f` with the values of `key`, if it's in the map.
+    pub fn get_and<Q, R>(&self, key: &Q, f: impl FnOnce(&[V]) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.handle.read(|map| map.get(key).map(|values| f(values)))
+    }
+
+    /// The first value of `key` that's still there.
+    pub fn get_one<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.get_and(key, |values| values[0].clone())
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.handle.read(|map| map.contains_key(key))
+    }
+
+    /// The number of keys.
+    pub fn len(&self) -> usize {
+        self.handle.read(|map| map.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handle.read(|map| map.is_empty())
+    }
+
+    /// The whole map, for example to iterate over it. Holds up
+    /// the writer's next `publish` until it's dropped.
+    pub fn enter(&self) -> ReadGuard<'_, Inner<K, V, S>> {
+        self.handle.enter()
+    }
+}
+
+impl<K, V, S> Clone for ReadHandle<K, V, S> {
+    fn clone(&self) -> Self {
+        Self { handle: self.handle.clone() }
+    }
+}
+
+#[test]
+fn main() {
+    let (mut w, r) = new();
+    w.insert("a", 1).insert("a", 2).insert("b", 3);
+    assert!(r.is_empty());
+    assert_eq!(w.read()["a"], [1, 2]);
+    assert_eq!(w.pending().len(), 3);
+    w.publish();
+    assert_eq!(r.len(), 2);
+    assert_eq!(r.get_and("a", |v| v.to_vec()), Some(vec![1, 2]));
+    assert_eq!(r.get_one("b"), Some(3));
+    assert_eq!(r.get_one("c"), None);
+
+    w.insert("a", 1).remove_value("a", 1);
+    // Published or not, both copies agree.
+    for _ in 0..2 {
+        w.publish();
+        assert_eq!(r.get_and("a", |v| v.to_vec()), Some(vec![2, 1]));
+    }
+
+    // A key without values is gone.
+    w.remove_value("a", 2).remove_value("a", 1).remove_value("b", 4).publish();
+    assert!(!r.contains_key("a"));
+    assert_eq!(r.get_one("b"), Some(3));
+    w.insert("c", 5).remove_entry("b").publish();
+    let keys = r.enter().keys().copied().collect::<Vec<_>>();
+    assert_eq!(keys, ["c"]);
+    w.clear().publish();
+    assert!(r.is_empty());
+    assert!(w.read().is_empty());
+}
+
+#[test]
+fn concurrent() {
+    use std::thread;
+
+    let (mut w, r) = new();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let r = r.clone();
+            s.spawn(move || loop {
+                // Every batch keeps all keys the same length.
+                let map = r.enter();
+                let lens = map.values().map(|v| v.len()).collect::<Vec<_>>();
+                assert!(lens.windows(2).all(|w| w[0] == w[1]));
+                if lens.first() == Some(&100) {
+                    break;
+                }
+            });
+        }
+        for i in 0..100 {
+            for k in 0..10 {
+                w.insert(k, i);
+            }
+            w.publish();
+        }
+    });
+}
@@ -0,0 +1,338 @@
+//! Left-right: two copies of the same data, one for the readers and one for the writer.
+//!
+//! The writer appends operations to a log, and applies them to its own copy right away.
+//! Nobody else can see that copy, so that doesn't need any synchronization. `publish` swaps
+//! the copies, waits for all readers that might still be reading the old one to leave it,
+//! and then applies the same operations to it, so it's ready to be the writer's copy.
+//!
+//! Readers never wait for anything: reading is incrementing a counter, loading which copy
+//! to read, reading it, and incrementing the counter again. Every `ReadHandle` has its own
+//! counter (its epoch), which is odd while it's reading. After a swap, `publish` only needs
+//! to wait for the readers whose epoch was odd, until it changes.
+//!
+//! Writes only become visible to readers on `publish`, all at once.
+//! See `map` for a multi-value map built on this, like evmap.
+
+pub mod map;
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use crate::cache_padded::CachePadded;
+use crate::sync::atomic::{fence, AtomicUsize};
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use crate::sync::{thread, Arc, Mutex, UnsafeCell};
+
+/// Data that operations of type `O` can be applied to.
+///
+/// Every operation is applied twice: once to each copy. Applying
+/// the same operations to the same data must give the same result.
+pub trait Absorb<O> {
+    /// Applies `op` to the writer's copy, right when it's appended.
+    fn absorb_first(&mut self, op: &O);
+
+    /// Applies `op` to the other copy, once it's been published.
+    ///
+    /// This is the last time we see the operation, so unlike `absorb_first`,
+    /// this can move things out of it rather than cloning them.
+    fn absorb_second(&mut self, op: O) {
+        self.absorb_first(&op);
+    }
+}
+
+struct Shared<T> {
+    copies: [UnsafeCell<T>; 2],
+    /// Which of the copies the readers read. The other one is the writer's.
+    read_index: AtomicUsize,
+    /// The epochs of all `ReadHandle`s, and of some that have been dropped already.
+    epochs: Mutex<Vec<Arc<Epoch>>>,
+}
+
+/// Odd while a `ReadHandle` is reading. Padded, since it's only written by its own reader.
+type Epoch = CachePadded<AtomicUsize>;
+
+// The writer only changes the copy that no reader looks at.
+unsafe impl<T: Send + Sync> Sync for Shared<T> {}
+
+pub struct WriteHandle<T, O> {
+    shared: Arc<Shared<T>>,
+    /// A copy of `shared.read_index`, which only we change.
+    read_index: usize,
+    /// Operations applied to our copy, but not yet to the other one.
+    log: Vec<O>,
+}
+
+/// Reads the copy of the data that was published last.
+///
+/// Can be cloned and sent to another thread, but can't be shared between threads:
+/// every thread needs its own handle.
+pub struct ReadHandle<T> {
+    shared: Arc<Shared<T>>,
+    epoch: Arc<Epoch>,
+    /// The number of `ReadGuard`s, since they can be nested.
+    guards: Cell<usize>,
+}
+
+/// The data as it was last published when this was created.
+///
+/// A `publish` that's already swapped the copies waits until this,
+/// and all other guards of the same `ReadHandle`, are dropped.
+pub struct ReadGuard<'a, T> {
+    handle: &'a ReadHandle<T>,
+    value: &'a T,
+    /// Must be dropped on the same thread as its `ReadHandle` was used on.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Creates the two copies of `value`, with a handle to write
+/// to them, and one to read them. Clone the latter for more readers.
+pub fn new<T: Absorb<O> + Clone, O>(value: T) -> (WriteHandle<T, O>, ReadHandle<T>) {
+    let shared = Arc::new(Shared {
+        copies: [UnsafeCell::new(value.clone()), UnsafeCell::new(value)],
+        read_index: AtomicUsize::new(0),
+        epochs: Mutex::new(Vec::new()),
+    });
+    let reader = ReadHandle::new(shared.clone());
+    (WriteHandle { shared, read_index: 0, log: Vec::new() }, reader)
+}
+
+impl<T: Absorb<O>, O> WriteHandle<T, O> {
+    /// Applies `op` to our copy, and logs it for the other copy. Readers
+    /// won't see it until the next `publish`.
+    pub fn append(&mut self, op: O) -> &mut Self {
+        // Safety: Readers don't look at our copy. See `publish`.
+        let copy = unsafe { &mut *self.shared.copies[1 - self.read_index].get() };
+        copy.absorb_first(&op);
+        self.log.push(op);
+        self
+    }
+
+    /// Makes everything appended so far visible to readers, all at once.
+    ///
+    /// Waits for all readers that were reading when this was called, but never for new ones.
+    pub fn publish(&mut self) -> &mut Self {
+        let shared = &*self.shared;
+        let new_read_index = 1 - self.read_index;
+        // Release, for readers to see the operations we applied to our copy.
+        shared.read_index.store(new_read_index, Release);
+        // Either a reader sees the new index, or we see that it incremented its epoch
+        // before loading the index. (Store-load, like the fence example from chapter 3.)
+        fence(SeqCst);
+        let epochs = {
+            let mut epochs = shared.epochs.lock().unwrap();
+            epochs.retain(|e| Arc::strong_count(e) > 1);
+            epochs.clone()
+        };
+        // A new reader can't get registered with an odd epoch, so there's
+        // no need to wait for readers that aren't in the list yet.
+        for epoch in epochs {
+            // Acquire, so all its reads of the old copy happen before we write to it.
+            let e = epoch.load(Acquire);
+            if e % 2 == 1 {
+                while epoch.load(Acquire) == e {
+                    thread::yield_now();
+                }
+            }
+        }
+        self.read_index = new_read_index;
+        // Safety: We just waited for all readers of this copy to leave it.
+        let copy = unsafe { &mut *shared.copies[1 - new_read_index].get() };
+        for op in self.log.drain(..) {
+            copy.absorb_second(op);
+        }
+        self
+    }
+
+    /// The operations appended since the last `publish`.
+    pub fn pending(&self) -> &[O] {
+        &self.log
+    }
+
+    /// Our own copy, including the operations that haven't been published yet.
+    pub fn read(&self) -> &T {
+        // Safety: Readers don't look at our copy, and we can't change it while this is borrowed.
+        unsafe { &*self.shared.copies[1 - self.read_index].get_const() }
+    }
+}
+
+impl<T> ReadHandle<T> {
+    fn new(shared: Arc<Shared<T>>) -> Self {
+        let epoch = Arc::new(CachePadded::new(AtomicUsize::new(0)));
+        shared.epochs.lock().unwrap().push(epoch.clone());
+        Self { shared, epoch, guards: Cell::new(0) }
+    }
+
+    /// Starts reading. This never waits, not even for a writer that's waiting for us.
+    pub fn enter(&self) -> ReadGuard<'_, T> {
+        let guards = self.guards.get();
+        if guards == 0 {
+            // Odd: reading.
+            self.epoch.fetch_add(1, Relaxed);
+            // See `publish`.
+            fence(SeqCst);
+        }
+        self.guards.set(guards + 1);
+        // Acquire, to see everything the writer did to this copy before it published it.
+        let index = self.shared.read_index.load(Acquire);
+        // Safety: The writer won't touch this copy until our epoch changes,
+        // which doesn't happen until all our guards are dropped.
+        let value = unsafe { &*self.shared.copies[index].get_const() };
+        ReadGuard { handle: self, value, _not_send: PhantomData }
+    }
+
+    /// Calls `f` with the copy that was published last.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.enter())
+    }
+}
+
+impl<T> Clone for ReadHandle<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.shared.clone())
+    }
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let guards = self.handle.guards.get() - 1;
+        self.handle.guards.set(guards);
+        if guards == 0 {
+            // Even: done reading. Release, so our reads happen before the writer's next writes.
+            self.handle.epoch.fetch_add(1, Release);
+        }
+    }
+}
+
+/// For tests: every operation adds to both halves, so readers should never see them differ.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Pair(u64, u64);
+
+#[cfg(test)]
+impl Absorb<u64> for Pair {
+    fn absorb_first(&mut self, op: &u64) {
+        self.0 += op;
+        self.1 += op;
+    }
+}
+
+#[test]
+fn main() {
+    let (mut w, r) = new(Pair::default());
+    w.append(1).append(2);
+    // Not published yet.
+    assert_eq!(r.enter().0, 0);
+    assert_eq!(w.read().0, 3);
+    assert_eq!(w.pending(), [1, 2]);
+    w.publish();
+    assert!(w.pending().is_empty());
+    assert_eq!(r.enter().0, 3);
+
+    // Both copies are up to date.
+    w.append(10).publish();
+    assert_eq!(r.read(|p| p.0), 13);
+    assert_eq!(w.read().0, 13);
+
+    // Nested guards see the same copy as the outer one, until it's dropped.
+    let a = r.enter();
+    let b = r.enter();
+    drop(b);
+    assert_eq!(a.0, 13);
+    drop(a);
+
+    // Dropped readers are removed from the list on the next publish.
+    let r2 = r.clone();
+    assert_eq!(w.shared.epochs.lock().unwrap().len(), 2);
+    drop(r2);
+    w.publish();
+    assert_eq!(w.shared.epochs.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn wait_free_readers() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    let (mut w, r) = new(Pair::default());
+    let r2 = r.clone();
+    let published = AtomicBool::new(false);
+    thread::scope(|s| {
+        let slow = r.enter();
+        s.spawn(|| {
+            w.append(1).publish();
+            published.store(true, Relaxed);
+        });
+        // The writer waits for us, but other readers don't: they read the new copy.
+        let other = s.spawn(move || {
+            while r2.enter().0 == 0 {
+                std::hint::spin_loop();
+            }
+            for _ in 0..1000 {
+                assert_eq!(r2.enter().0, 1);
+            }
+        });
+        other.join().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(!published.load(Relaxed));
+        assert_eq!(slow.0, 0);
+        drop(slow);
+    });
+    assert!(published.load(Relaxed));
+}
+
+#[test]
+fn concurrent() {
+    use std::thread;
+
+    let (mut w, r) = new(Pair::default());
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let r = r.clone();
+            s.spawn(move || {
+                let mut last = 0;
+                loop {
+                    let p = r.enter();
+                    // Never a half-applied operation, nor going back in time.
+                    assert_eq!(p.0, p.1);
+                    assert!(p.0 >= last);
+                    last = p.0;
+                    if last == 1000 {
+                        break;
+                    }
+                }
+            });
+        }
+        for _ in 0..1000 {
+            w.append(1).publish();
+        }
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::thread;
+
+    // Every interleaving takes most of a minute. A torn pair takes two preemptions: the
+    // reader switched out in the middle of a read, and back in once the writer changed
+    // the copy it was reading. Five also leave room for that to happen in the second publish.
+    crate::sync::model_bounded(5, || {
+        let (mut w, r) = new(Pair::default());
+        let t = thread::spawn(move || {
+            let a = r.read(|p| (p.0, p.1));
+            let b = r.read(|p| (p.0, p.1));
+            assert!(a.0 == a.1 && b.0 == b.1 && a.0 <= b.0);
+        });
+        w.append(1).publish();
+        w.append(1).publish();
+        t.join().unwrap();
+    });
+}
@@ -2,3 +2,14 @@ pub mod ch4_spin_lock;
 pub mod ch5_channels;
 pub mod ch6_arc;
 pub mod ch9_locks;
+pub mod collections;
+pub mod concurrent_map;
+pub mod futex;
+pub mod left_right;
+pub mod reclaim;
+pub mod seqlock;
+pub mod sharded_map;
+
+mod cache_padded;
+mod lock_order;
+mod sync;
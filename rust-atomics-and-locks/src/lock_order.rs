@@ -0,0 +1,379 @@
+//! Deadlock detection for `SpinLock` and the `mutex_3`, `rwlock_3` and `reentrant_mutex` locks,
+//! enabled by the `deadlock-detection` feature.
+//!
+//! Every time a thread locks a lock while holding others, that order is added to a global graph:
+//! an edge from every lock it holds to the one it's locking. A thread about to lock `b`
+//! while holding `a` after another thread (or the same one, earlier) locked `a` while
+//! holding `b` can deadlock, even if it happens to not do so this time. That closes a cycle
+//! in the graph, and we panic before even trying to lock, with the backtraces of both.
+//!
+//! Locks are identified by their address, so a lock that's moved after having been
+//! locked leaves its edges behind, until something else is put at its old address.
+//!
+//! Without the feature, all of this is empty functions on a zero-sized type.
+
+#[cfg(feature = "deadlock-detection")]
+pub(crate) use enabled::LockNode;
+
+#[cfg(not(feature = "deadlock-detection"))]
+pub(crate) use disabled::LockNode;
+
+/// How a thread holds (or is about to lock) a lock.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// A read lock. A thread may hold more than one of these at a time.
+    Shared,
+    /// A read lock that can be upgraded to a write lock, of which there can only be one at a time.
+    Upgradable,
+    Exclusive,
+}
+
+#[cfg(feature = "deadlock-detection")]
+impl Access {
+    /// Whether a thread that holds a lock like this can also lock it like `other`.
+    fn compatible(self, other: Access) -> bool {
+        use Access::*;
+        matches!((self, other), (Shared, Shared) | (Shared, Upgradable) | (Upgradable, Shared))
+    }
+}
+
+#[cfg(feature = "deadlock-detection")]
+mod enabled {
+    use std::backtrace::Backtrace;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::panic::Location;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread;
+    use super::Access;
+
+    /// Part of a lock. Its address identifies the lock.
+    pub(crate) struct LockNode {
+        /// Makes sure it has a unique address, even inside a lock that's otherwise zero-sized.
+        _nonzero: u8,
+    }
+
+    /// The first time a thread locked `to` while holding `from`.
+    struct Edge {
+        /// Where `from` was locked.
+        from: &'static Location<'static>,
+        /// Where `to` was locked.
+        to: &'static Location<'static>,
+        backtrace: Backtrace,
+    }
+
+    struct Held {
+        node: usize,
+        location: &'static Location<'static>,
+        access: Access,
+    }
+
+    /// For every lock, the locks that have been locked while holding it.
+    type Graph = BTreeMap<usize, BTreeMap<usize, Arc<Edge>>>;
+
+    static GRAPH: Mutex<Graph> = Mutex::new(BTreeMap::new());
+
+    thread_local! {
+        /// The locks held by this thread, in the order they were locked.
+        static HELD: RefCell<Vec<Held>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn graph() -> MutexGuard<'static, Graph> {
+        // A panic while holding it can only come from an allocation failure,
+        // and doesn't leave the graph in a broken state.
+        GRAPH.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    impl LockNode {
+        pub const fn new() -> Self {
+            Self { _nonzero: 0 }
+        }
+
+        fn address(&self) -> usize {
+            self as *const Self as usize
+        }
+
+        /// To be called right before blocking to lock it.
+        /// Not needed for a `try_lock`, which can't deadlock.
+        ///
+        /// Panics if that could deadlock. A thread may take a `Shared` (read) lock
+        /// more than once, and one `Upgradable` lock with those, but nothing else
+        /// when it already holds it.
+        #[track_caller]
+        pub fn before_lock(&self, access: Access) {
+            let node = self.address();
+            let location = Location::caller();
+            let report = HELD.with(|held| {
+                let held = held.borrow();
+                if let Some(h) = held.iter().find(|h| h.node == node && !h.access.compatible(access)) {
+                    return Some(format!(
+                        "deadlock: locking {node:#x} at {location}, \
+                        but this thread already locked it at {}",
+                        h.location,
+                    ));
+                }
+                let mut graph = graph();
+                for h in held.iter().filter(|h| h.node != node) {
+                    if graph.get(&h.node).is_some_and(|e| e.contains_key(&node)) {
+                        continue;
+                    }
+                    if let Some(path) = find_path(&graph, node, h.node) {
+                        return Some(describe(h, node, location, &path));
+                    }
+                    let edge = Edge { from: h.location, to: location, backtrace: Backtrace::force_capture() };
+                    graph.entry(h.node).or_default().insert(node, Arc::new(edge));
+                }
+                None
+            });
+            if let Some(report) = report {
+                if thread::panicking() {
+                    // Panicking again would abort.
+                    eprintln!("{report}");
+                } else {
+                    panic!("{report}");
+                }
+            }
+        }
+
+        /// To be called right after locking it.
+        #[track_caller]
+        pub fn locked(&self, access: Access) {
+            let held = Held { node: self.address(), location: Location::caller(), access };
+            HELD.with(|h| h.borrow_mut().push(held));
+        }
+
+        /// To be called when this thread changes how it holds the lock, from `from` to `to`,
+        /// without unlocking it: an upgrade or a downgrade. For an upgrade, that's before
+        /// waiting for the other readers to leave, since it panics if this thread is one of them.
+        #[track_caller]
+        pub fn changed(&self, from: Access, to: Access) {
+            let node = self.address();
+            let location = Location::caller();
+            let report = HELD.with(|held| {
+                let mut held = held.borrow_mut();
+                let i = held.iter().rposition(|h| h.node == node && h.access == from)?;
+                let other = held.iter().enumerate().find(|&(j, h)| {
+                    j != i && h.node == node && !h.access.compatible(to)
+                });
+                if let Some((_, h)) = other {
+                    return Some(format!(
+                        "deadlock: upgrading {node:#x} at {location}, \
+                        but this thread also locked it at {}",
+                        h.location,
+                    ));
+                }
+                held[i].access = to;
+                None
+            });
+            if let Some(report) = report {
+                panic!("{report}");
+            }
+        }
+
+        /// To be called right before (or after) unlocking it.
+        pub fn unlocked(&self) {
+            let node = self.address();
+            // A guard might be dropped while the thread-local is being destroyed.
+            let _ = HELD.try_with(|held| {
+                let mut held = held.borrow_mut();
+                // Locks aren't always unlocked in the reverse order of locking.
+                if let Some(i) = held.iter().rposition(|h| h.node == node) {
+                    held.remove(i);
+                }
+            });
+        }
+    }
+
+    impl Drop for LockNode {
+        fn drop(&mut self) {
+            let node = self.address();
+            let mut graph = graph();
+            graph.remove(&node);
+            graph.retain(|_, edges| {
+                edges.remove(&node);
+                !edges.is_empty()
+            });
+        }
+    }
+
+    /// The edges of a path from `from` to `to`, if there is one.
+    fn find_path(graph: &Graph, from: usize, to: usize) -> Option<Vec<(usize, usize, Arc<Edge>)>> {
+        // Depth first, remembering how we got to every node we've seen.
+        let mut came_from = BTreeMap::new();
+        let mut stack = vec![from];
+        while let Some(n) = stack.pop() {
+            if n == to {
+                let mut path = Vec::new();
+                let mut n = to;
+                while n != from {
+                    let prev = came_from[&n];
+                    path.push((prev, n, graph[&prev][&n].clone()));
+                    n = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &next in graph.get(&n).into_iter().flat_map(|e| e.keys()) {
+                if next != from && !came_from.contains_key(&next) {
+                    came_from.insert(next, n);
+                    stack.push(next);
+                }
+            }
+        }
+        None
+    }
+
+    fn describe(
+        held: &Held,
+        node: usize,
+        location: &Location<'_>,
+        path: &[(usize, usize, Arc<Edge>)],
+    ) -> String {
+        let mut r = format!(
+            "potential deadlock: locking {node:#x} at {location} while holding {:#x} (locked at {}), \
+            but they have been locked in the opposite order before:\n",
+            held.node, held.location,
+        );
+        for (from, to, edge) in path {
+            let _ = writeln!(r, "  {to:#x} locked at {} while holding {from:#x} (locked at {})", edge.to, edge.from);
+        }
+        for (from, to, edge) in path {
+            let _ = writeln!(r, "\nbacktrace of locking {to:#x} while holding {from:#x}:\n{}", edge.backtrace);
+        }
+        let _ = writeln!(r, "\nbacktrace of this attempt:\n{}", Backtrace::force_capture());
+        r
+    }
+}
+
+#[cfg(not(feature = "deadlock-detection"))]
+mod disabled {
+    use super::Access;
+
+    pub(crate) struct LockNode;
+
+    impl LockNode {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        #[inline(always)]
+        pub fn before_lock(&self, _access: Access) {}
+
+        #[inline(always)]
+        pub fn locked(&self, _access: Access) {}
+
+        #[inline(always)]
+        pub fn changed(&self, _from: Access, _to: Access) {}
+
+        #[inline(always)]
+        pub fn unlocked(&self) {}
+    }
+}
+
+#[cfg(feature = "deadlock-detection")]
+#[test]
+fn main() {
+    use crate::ch4_spin_lock::s3_guard::SpinLock;
+    use crate::ch9_locks::mutex_3::Mutex;
+    use crate::ch9_locks::rwlock_3::RwLock;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn message(e: Box<dyn std::any::Any + Send>) -> String {
+        *e.downcast::<String>().unwrap()
+    }
+
+    for _ in 0..2 {
+        // Scoped, to check that the next iteration's locks (likely at
+        // the same addresses) don't inherit any of the old edges.
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+        let c = RwLock::new(0);
+        let d = SpinLock::new(0);
+
+        // Consistent order: fine.
+        for _ in 0..2 {
+            let _a = a.lock().unwrap();
+            let _b = b.lock().unwrap();
+            let _c = c.read().unwrap();
+        }
+
+        // Unlocking in a different order doesn't matter.
+        let ga = a.lock().unwrap();
+        let gb = b.lock().unwrap();
+        drop(ga);
+        drop(gb);
+
+        // b, then a: the opposite of before. Reported before it actually deadlocks.
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let _b = b.lock().unwrap();
+            let _a = a.lock().unwrap();
+        }))
+        .unwrap_err();
+        let e = message(e);
+        assert!(e.starts_with("potential deadlock"));
+        assert_eq!(e.matches("backtrace of").count(), 2);
+        assert!(e.contains(file!()));
+        b.clear_poison();
+
+        // A longer cycle: d, then a, after a -> b -> c earlier and c -> d now.
+        {
+            let _c = c.write().unwrap();
+            let _d = d.lock();
+        }
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let _d = d.lock();
+            let _a = a.lock().unwrap();
+        }))
+        .unwrap_err();
+        // a -> c -> d, or a -> b -> c -> d, plus this attempt.
+        assert!(message(e).matches("backtrace of").count() >= 3);
+
+        // try_lock can't deadlock, so it doesn't count.
+        {
+            let _b = b.lock().unwrap();
+            let _a = a.try_lock().unwrap();
+        }
+
+        // Locking a lock twice.
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let _c = c.read().unwrap();
+            let _c = c.write().unwrap();
+        }))
+        .unwrap_err();
+        assert!(message(e).contains("already locked it"));
+
+        // Only one upgradable read at a time, even on one thread.
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let _c = c.upgradable_read().unwrap();
+            let _c = c.upgradable_read().unwrap();
+        }))
+        .unwrap_err();
+        assert!(message(e).contains("already locked it"));
+
+        // Reading alongside it is fine, but then it can't be upgraded.
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let u = c.upgradable_read().unwrap();
+            let _r = c.read().unwrap();
+            u.upgrade();
+        }))
+        .unwrap_err();
+        assert!(message(e).contains("upgrading"));
+
+        // Once upgraded, it's a write lock.
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            let _w = c.upgradable_read().unwrap().upgrade();
+            let _r = c.read().unwrap();
+        }))
+        .unwrap_err();
+        assert!(message(e).contains("already locked it"));
+        c.clear_poison();
+
+        // And once downgraded, a read lock again.
+        {
+            let r = c.upgradable_read().unwrap().upgrade().downgrade();
+            let _r = c.read().unwrap();
+            drop(r);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::Mutex;
+use crate::cache_padded::CachePadded;
+use super::Deferred;
+
+/// Epoch-based reclamation.
+///
+/// There's a global epoch counter. Every thread that's about to access the data
+/// structure pins itself to the current epoch, and garbage is tagged with the epoch
+/// in which it was removed. The global epoch only advances when all pinned threads
+/// are pinned to the current one. So, once it has advanced twice past the tag of some
+/// garbage, no thread can still be pinned from before that garbage was removed.
+pub struct Collector {
+    epoch: CachePadded<AtomicUsize>,
+    /// A linked list of all participants, ever. Only freed when the collector is dropped.
+    participants: AtomicPtr<Participant>,
+    /// Garbage left behind by handles that were dropped before it could be freed.
+    orphans: Mutex<Vec<(usize, Deferred)>>,
+}
+
+struct Participant {
+    /// The pinned epoch times two plus one, or zero if not pinned.
+    state: CachePadded<AtomicUsize>,
+    /// Whether this participant belongs to a `LocalHandle`.
+    in_use: AtomicBool,
+    /// Never changes after this participant is added to the list.
+    next: *mut Participant,
+}
+
+/// A thread's registration with a `Collector`.
+pub struct LocalHandle<'a> {
+    collector: &'a Collector,
+    participant: &'a Participant,
+    /// The number of `Guard`s, since pinning can be nested.
+    guards: Cell<usize>,
+    pins: Cell<usize>,
+    /// Our own garbage, with the epoch it was removed in.
+    garbage: RefCell<Vec<(usize, Deferred)>>,
+}
+
+/// Proof that the thread is pinned. Pointers loaded
+/// while this exists won't be freed until it's dropped.
+pub struct Guard<'a> {
+    handle: &'a LocalHandle<'a>,
+}
+
+/// The collector used by `pin`.
+static DEFAULT: Collector = Collector::new();
+
+thread_local! {
+    static HANDLE: LocalHandle<'static> = DEFAULT.register();
+}
+
+/// Pins the current thread using a global collector, for the duration of `f`.
+///
+/// Every thread gets its own handle the first time it calls this.
+pub fn pin<R>(f: impl FnOnce(&Guard<'_>) -> R) -> R {
+    let mut f = Some(f);
+    HANDLE
+        .try_with(|handle| f.take().unwrap()(&handle.pin()))
+        // The thread's handle is already gone when called from another thread local's destructor.
+        .unwrap_or_else(|_| f.take().unwrap()(&DEFAULT.register().pin()))
+}
+
+/// Try to advance the epoch and free garbage every this many pins.
+const PINS_BETWEEN_COLLECT: usize = 128;
+/// Or when a handle has collected this much garbage.
+const MAX_GARBAGE: usize = 64;
+
+// Safety: The only non-Sync part of a Participant is `next`, which never changes once shared.
+unsafe impl Sync for Collector {}
+unsafe impl Send for Collector {}
+
+impl Collector {
+    pub const fn new() -> Self {
+        Self {
+            epoch: CachePadded::new(AtomicUsize::new(0)),
+            participants: AtomicPtr::new(ptr::null_mut()),
+            orphans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers the current thread. Every thread needs its own handle to pin.
+    pub fn register(&self) -> LocalHandle<'_> {
+        let participant = self.find_participant().unwrap_or_else(|| self.add_participant());
+        LocalHandle {
+            collector: self,
+            participant,
+            guards: Cell::new(0),
+            pins: Cell::new(0),
+            garbage: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn participants(&self) -> impl Iterator<Item = &Participant> {
+        let mut p = self.participants.load(Acquire);
+        std::iter::from_fn(move || {
+            // Safety: Participants are only freed when the collector is dropped.
+            let participant = unsafe { p.as_ref()? };
+            p = participant.next;
+            Some(participant)
+        })
+    }
+
+    /// Reuses the participant of a dropped handle.
+    fn find_participant(&self) -> Option<&Participant> {
+        self.participants().find(|p| {
+            !p.in_use.load(Relaxed)
+                && p.in_use.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+        })
+    }
+
+    fn add_participant(&self) -> &Participant {
+        let new = Box::into_raw(Box::new(Participant {
+            state: CachePadded::new(AtomicUsize::new(0)),
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.participants.load(Relaxed);
+        loop {
+            // Safety: Nobody else has seen this participant yet.
+            unsafe { (*new).next = head };
+            // Release, to publish the participant.
+            match self.participants.compare_exchange_weak(head, new, Release, Relaxed) {
+                Ok(_) => return unsafe { &*new },
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Advances the global epoch if all pinned participants have seen
+    /// the current one. Returns the (possibly new) global epoch.
+    fn try_advance(&self) -> usize {
+        let global = self.epoch.load(Relaxed);
+        // Pairs with the fence in `pin`: either we see that participant as pinned,
+        // or it sees the current epoch (or a later one), and everything from before it.
+        fence(SeqCst);
+        for p in self.participants() {
+            let state = p.state.load(Relaxed);
+            if state & 1 == 1 && state >> 1 != global {
+                return global;
+            }
+        }
+        // Acquire, to make sure everything those participants did
+        // while pinned to the previous epoch happened before this.
+        fence(Acquire);
+        match self.epoch.compare_exchange(global, global + 1, Release, Relaxed) {
+            Ok(_) => global + 1,
+            Err(e) => e,
+        }
+    }
+
+    /// Tags garbage that was just removed, and so can only be freed after
+    /// the global epoch has advanced twice from here.
+    fn current_tag(&self) -> usize {
+        // Make sure the removal (by the caller) happens before reading the epoch.
+        fence(SeqCst);
+        self.epoch.load(Relaxed)
+    }
+
+    fn collect_orphans(&self, global: usize) {
+        let ready = match self.orphans.try_lock() {
+            Ok(mut orphans) => take_ready(&mut orphans, global),
+            Err(_) => return,
+        };
+        ready.into_iter().for_each(Deferred::run);
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Removes and returns everything that's safe to free once the epoch is `global`.
+fn take_ready(garbage: &mut Vec<(usize, Deferred)>, global: usize) -> Vec<Deferred> {
+    let (ready, not_ready) = mem::take(garbage)
+        .into_iter()
+        .partition::<Vec<_>, _>(|&(tag, _)| tag + 2 <= global);
+    *garbage = not_ready;
+    ready.into_iter().map(|(_, d)| d).collect()
+}
+
+impl<'a> LocalHandle<'a> {
+    pub fn pin(&self) -> Guard<'_> {
+        let n = self.guards.get();
+        self.guards.set(n + 1);
+        if n == 0 {
+            let global = self.collector.epoch.load(Relaxed);
+            self.participant.state.store(global << 1 | 1, Relaxed);
+            // Make sure we're seen as pinned before we load anything from the structure.
+            fence(SeqCst);
+            let pins = self.pins.get().wrapping_add(1);
+            self.pins.set(pins);
+            if pins % PINS_BETWEEN_COLLECT == 0 {
+                self.collect();
+            }
+        }
+        Guard { handle: self }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.guards.get() > 0
+    }
+
+    fn defer(&self, deferred: Deferred) {
+        let tag = self.collector.current_tag();
+        let len = {
+            let mut garbage = self.garbage.borrow_mut();
+            garbage.push((tag, deferred));
+            garbage.len()
+        };
+        if len >= MAX_GARBAGE {
+            self.collect();
+        }
+    }
+
+    /// Tries to advance the epoch, and frees whatever garbage that allows.
+    fn collect(&self) {
+        let global = self.collector.try_advance();
+        let ready = take_ready(&mut self.garbage.borrow_mut(), global);
+        // Not while borrowing `garbage`, in case these defer more garbage themselves.
+        ready.into_iter().for_each(Deferred::run);
+        self.collector.collect_orphans(global);
+    }
+}
+
+impl Drop for LocalHandle<'_> {
+    fn drop(&mut self) {
+        let garbage = mem::take(self.garbage.get_mut());
+        self.collector.orphans.lock().unwrap().extend(garbage);
+        // Release, so the next handle to use this participant sees our last unpin.
+        self.participant.in_use.store(false, Release);
+    }
+}
+
+impl Guard<'_> {
+    /// Drops the `Box` that `ptr` came from, once no thread
+    /// can be accessing it anymore.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable for
+    /// threads that pin after this, and must not be destroyed by anything else.
+    /// Unless `T: 'static`, dropping the `T` must not use anything it borrows that
+    /// might be gone before it's dropped, which can be as late as dropping the `Collector`.
+    pub unsafe fn defer_destroy<T: Send>(&self, ptr: *mut T) {
+        self.handle.defer(Deferred::destroy(ptr));
+    }
+
+    /// Runs `f` once no thread that is pinned right now is still pinned.
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.handle.defer(Deferred::new(f));
+    }
+
+    /// Tries to free garbage right now, rather than
+    /// waiting for a later `pin` or `defer` to do so.
+    pub fn flush(&self) {
+        self.handle.collect();
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        let n = self.handle.guards.get() - 1;
+        self.handle.guards.set(n);
+        if n == 0 {
+            // Release, so everything we did while pinned happens
+            // before the epoch advances past us.
+            self.handle.participant.state.store(0, Release);
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        // No handles or guards are left, so nothing is pinned.
+        for (_, deferred) in mem::take(self.orphans.get_mut().unwrap()) {
+            deferred.run();
+        }
+        let mut p = *self.participants.get_mut();
+        while !p.is_null() {
+            // Safety: We're the only one left who can access the participants.
+            let participant = unsafe { Box::from_raw(p) };
+            p = participant.next;
+        }
+    }
+}
+
+#[test]
+fn main() {
+    struct DetectDrop<'a>(&'a AtomicUsize);
+
+    impl Drop for DetectDrop<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    let drops = AtomicUsize::new(0);
+    let collector = Collector::new();
+    let handle = collector.register();
+    {
+        let guard = handle.pin();
+        let p = Box::into_raw(Box::new(DetectDrop(&drops)));
+        unsafe { guard.defer_destroy(p) };
+        // We're still pinned, so this can't be freed yet.
+        guard.flush();
+        assert_eq!(drops.load(Relaxed), 0);
+    }
+    // Once unpinned, the epoch can advance past it.
+    for _ in 0..3 {
+        handle.pin().flush();
+    }
+    assert_eq!(drops.load(Relaxed), 1);
+
+    // Whatever is left is freed when the collector is dropped.
+    let p = Box::into_raw(Box::new(DetectDrop(&drops)));
+    unsafe { handle.pin().defer_destroy(p) };
+    drop(handle);
+    drop(collector);
+    assert_eq!(drops.load(Relaxed), 2);
+}
+
+#[test]
+fn concurrent() {
+    use std::sync::atomic::Ordering::AcqRel;
+    use std::thread;
+
+    let iterations = if cfg!(miri) { 100 } else { 10_000 };
+    let collector = Collector::new();
+    let shared = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                let handle = collector.register();
+                for _ in 0..iterations {
+                    let _guard = handle.pin();
+                    // Safety: Nothing we load while pinned is freed before we unpin.
+                    let n = unsafe { *shared.load(Acquire) };
+                    assert!(n < iterations);
+                }
+            });
+        }
+        s.spawn(|| {
+            let handle = collector.register();
+            for i in 1..iterations {
+                let guard = handle.pin();
+                let old = shared.swap(Box::into_raw(Box::new(i)), AcqRel);
+                unsafe { guard.defer_destroy(old) };
+            }
+        });
+    });
+    drop(unsafe { Box::from_raw(shared.into_inner()) });
+}
@@ -0,0 +1,269 @@
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use super::Deferred;
+
+/// Hazard pointers.
+///
+/// Before accessing a node, a thread stores its address in one of its hazard
+/// pointers, and then checks that the node is still reachable. Retired nodes are
+/// only freed once no hazard pointer points to them.
+pub struct Domain {
+    /// A linked list of all slots, ever. Only freed when the domain is dropped.
+    slots: AtomicPtr<Slot>,
+    /// A linked list (stack) of retired nodes.
+    retired: AtomicPtr<Retired>,
+    num_retired: AtomicUsize,
+}
+
+struct Slot {
+    hazard: AtomicPtr<u8>,
+    /// Whether this slot belongs to a `HazardPointer`.
+    in_use: AtomicBool,
+    /// Never changes after this slot is added to the list.
+    next: *mut Slot,
+}
+
+struct Retired {
+    /// To compare against the hazard pointers.
+    ptr: *mut u8,
+    deferred: Deferred,
+    next: *mut Retired,
+}
+
+/// Protects one pointer at a time from being freed.
+pub struct HazardPointer<'a> {
+    slot: &'a Slot,
+}
+
+/// Scan the hazard pointers once this many nodes have been retired.
+const RECLAIM_THRESHOLD: usize = 64;
+
+// Safety: The only non-Sync part of a Slot is `next`, which never changes once shared.
+// The nodes in `retired` are Send.
+unsafe impl Sync for Domain {}
+unsafe impl Send for Domain {}
+
+impl Domain {
+    pub const fn new() -> Self {
+        Self {
+            slots: AtomicPtr::new(ptr::null_mut()),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            num_retired: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn hazard_pointer(&self) -> HazardPointer<'_> {
+        let slot = self.find_slot().unwrap_or_else(|| self.add_slot());
+        HazardPointer { slot }
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        let mut p = self.slots.load(Acquire);
+        std::iter::from_fn(move || {
+            // Safety: Slots are only freed when the domain is dropped.
+            let slot = unsafe { p.as_ref()? };
+            p = slot.next;
+            Some(slot)
+        })
+    }
+
+    /// Reuses the slot of a dropped hazard pointer.
+    fn find_slot(&self) -> Option<&Slot> {
+        self.slots().find(|s| {
+            !s.in_use.load(Relaxed)
+                && s.in_use.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+        })
+    }
+
+    fn add_slot(&self) -> &Slot {
+        let new = Box::into_raw(Box::new(Slot {
+            hazard: AtomicPtr::new(ptr::null_mut()),
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.slots.load(Relaxed);
+        loop {
+            // Safety: Nobody else has seen this slot yet.
+            unsafe { (*new).next = head };
+            match self.slots.compare_exchange_weak(head, new, Release, Relaxed) {
+                Ok(_) => return unsafe { &*new },
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Drops the `Box` that `ptr` came from, once no hazard pointer points to it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable
+    /// for other threads, and must not be retired more than once.
+    /// Unless `T: 'static`, dropping the `T` must not use anything it borrows that
+    /// might be gone before it's dropped, which can be as late as dropping the `Domain`.
+    pub unsafe fn retire<T: Send>(&self, ptr: *mut T) {
+        let node = Box::into_raw(Box::new(Retired {
+            ptr: ptr as *mut u8,
+            deferred: Deferred::destroy(ptr),
+            next: ptr::null_mut(),
+        }));
+        self.push_retired(node);
+        if self.num_retired.fetch_add(1, Relaxed) + 1 >= RECLAIM_THRESHOLD {
+            self.reclaim();
+        }
+    }
+
+    fn push_retired(&self, node: *mut Retired) {
+        let mut head = self.retired.load(Relaxed);
+        loop {
+            // Safety: This node isn't shared (anymore or yet).
+            unsafe { (*node).next = head };
+            // Release, so whoever takes this node sees it was unlinked before retiring.
+            match self.retired.compare_exchange_weak(head, node, Release, Relaxed) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Frees all retired nodes that no hazard pointer points to.
+    pub fn reclaim(&self) {
+        let mut node = self.retired.swap(ptr::null_mut(), Acquire);
+        if node.is_null() {
+            return;
+        }
+        // Pairs with the fence in `protect`: either we see the hazard
+        // pointer, or that thread sees that the node was unlinked.
+        fence(SeqCst);
+        let protected: Vec<*mut u8> = self.slots()
+            .map(|s| s.hazard.load(Relaxed))
+            .filter(|p| !p.is_null())
+            .collect();
+        // Pairs with the Release in `protect` and `reset`: a thread that moved its hazard
+        // pointer away from a node is done with it before we free it below.
+        fence(Acquire);
+        let mut freed = 0;
+        while !node.is_null() {
+            // Safety: We took these nodes out of the list, so they're ours now.
+            let next = unsafe { (*node).next };
+            if protected.contains(&unsafe { (*node).ptr }) {
+                self.push_retired(node);
+            } else {
+                let retired = unsafe { Box::from_raw(node) };
+                retired.deferred.run();
+                freed += 1;
+            }
+            node = next;
+        }
+        self.num_retired.fetch_sub(freed, Relaxed);
+    }
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HazardPointer<'_> {
+    /// Loads the pointer from `src`, and protects it from being freed
+    /// until this hazard pointer is reset or used to protect something else.
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> *mut T {
+        let mut p = src.load(Relaxed);
+        loop {
+            // Release, so our accesses to the node we protected before
+            // happen before it's freed. (Like `reset`.)
+            self.slot.hazard.store(p as *mut u8, Release);
+            // Make sure our hazard pointer is visible before we check `src` again.
+            fence(SeqCst);
+            // Acquire, to see the contents of the node.
+            let q = src.load(Acquire);
+            if q == p {
+                // Still reachable after publishing the hazard pointer,
+                // so it can't have been retired before `reclaim` looks at it.
+                return p;
+            }
+            p = q;
+        }
+    }
+
+    pub fn reset(&self) {
+        // Release, so our accesses to the node happen before it's freed.
+        self.slot.hazard.store(ptr::null_mut(), Release);
+    }
+}
+
+impl Drop for HazardPointer<'_> {
+    fn drop(&mut self) {
+        self.reset();
+        self.slot.in_use.store(false, Release);
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        // No hazard pointers are left, so nothing is protected.
+        let mut node = mem::replace(self.retired.get_mut(), ptr::null_mut());
+        while !node.is_null() {
+            let retired = unsafe { Box::from_raw(node) };
+            node = retired.next;
+            retired.deferred.run();
+        }
+        let mut p = *self.slots.get_mut();
+        while !p.is_null() {
+            // Safety: We're the only one left who can access the slots.
+            let slot = unsafe { Box::from_raw(p) };
+            p = slot.next;
+        }
+    }
+}
+
+#[test]
+fn main() {
+    let domain = Domain::new();
+    let shared = AtomicPtr::new(Box::into_raw(Box::new(1)));
+    let hp = domain.hazard_pointer();
+    let p = hp.protect(&shared);
+    // Unlink and retire it, while it's still protected.
+    shared.store(Box::into_raw(Box::new(2)), Release);
+    unsafe { domain.retire(p) };
+    domain.reclaim();
+    assert_eq!(domain.num_retired.load(Relaxed), 1);
+    assert_eq!(unsafe { *p }, 1);
+    hp.reset();
+    domain.reclaim();
+    assert_eq!(domain.num_retired.load(Relaxed), 0);
+    drop(hp);
+    // Retired nodes left behind are freed when the domain is dropped.
+    unsafe { domain.retire(shared.into_inner()) };
+}
+
+#[test]
+fn concurrent() {
+    use std::sync::atomic::Ordering::AcqRel;
+    use std::thread;
+
+    let iterations = if cfg!(miri) { 100 } else { 10_000 };
+    let domain = Domain::new();
+    let shared = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                let hp = domain.hazard_pointer();
+                for _ in 0..iterations {
+                    // Safety: Protected by the hazard pointer.
+                    let n = unsafe { *hp.protect(&shared) };
+                    assert!(n < iterations);
+                }
+            });
+        }
+        s.spawn(|| {
+            for i in 1..iterations {
+                let old = shared.swap(Box::into_raw(Box::new(i)), AcqRel);
+                unsafe { domain.retire(old) };
+            }
+        });
+    });
+    drop(unsafe { Box::from_raw(shared.into_inner()) });
+}
@@ -0,0 +1,51 @@
+//! Memory reclamation for lock-free data structures.
+//!
+//! A thread that removes a node from a lock-free structure can't free it right away:
+//! other threads might have loaded a pointer to it just before, and still be reading it.
+//! `Arc` solves this with a reference counter per node, but that makes every read a
+//! (contended) read-modify-write operation. These modules defer freeing instead:
+//!
+//! - `epoch`: Readers "pin" the current epoch while accessing the structure.
+//!   Removed nodes are only freed once every thread has been seen unpinned or
+//!   in a later epoch. Cheap for readers, but one stalled thread stops all reclamation.
+//! - `hazard`: Readers announce every single pointer they're accessing,
+//!   and removed nodes are only freed once no thread announces them.
+//!   More work per access, but a stalled thread only holds on to a few nodes.
+
+pub mod epoch;
+pub mod hazard;
+
+/// A type-erased action to run later, such as dropping a `Box<T>`.
+struct Deferred {
+    data: *mut u8,
+    call: unsafe fn(*mut u8),
+}
+
+// Safety: Only ever created from things that are Send.
+unsafe impl Send for Deferred {}
+
+impl Deferred {
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, and nothing may use it after this runs.
+    /// Since this can run much later, dropping the `T` must not use anything it borrows
+    /// that might be gone by then. (Which is nothing, if `T: 'static`.)
+    unsafe fn destroy<T: Send>(ptr: *mut T) -> Self {
+        unsafe fn drop_box<T>(data: *mut u8) {
+            drop(Box::from_raw(data as *mut T));
+        }
+        Self { data: ptr as *mut u8, call: drop_box::<T> }
+    }
+
+    fn new<F: FnOnce() + Send + 'static>(f: F) -> Self {
+        unsafe fn call_box<F: FnOnce()>(data: *mut u8) {
+            Box::from_raw(data as *mut F)();
+        }
+        Self { data: Box::into_raw(Box::new(f)) as *mut u8, call: call_box::<F> }
+    }
+
+    fn run(self) {
+        // Safety: `destroy`'s caller promised this is fine, or it's our own boxed `'static` closure.
+        unsafe { (self.call)(self.data) }
+    }
+}
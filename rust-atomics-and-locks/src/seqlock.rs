@@ -0,0 +1,256 @@
+//! A sequence lock: a single writer, and readers that never block it.
+//!
+//! The writer makes the sequence number odd, writes, and makes it even again. Readers copy
+//! the data without any locking, and just try again if the sequence number changed while
+//! they were copying, or was odd to begin with. Good for small data that's read much more
+//! often than it's written, like statistics that must be read together (unlike those of
+//! `examples/ch2-07-statistics.rs`, which a reader can see half updated).
+//!
+//! Since readers copy the data while the writer might be writing it, that has to be done
+//! with atomics, or it'd be a data race, even if the torn copy is never used. So the data is
+//! stored as atomic bytes, and can only be of a type that's nothing but bytes: see `NoUninit`.
+
+use std::marker::PhantomData;
+use std::mem::{size_of, MaybeUninit};
+use crate::sync::atomic::{fence, AtomicBool, AtomicU8, AtomicUsize};
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::hint;
+
+/// Types without any padding or other uninitialized bytes, which makes
+/// it possible to copy them byte by byte, using atomics.
+///
+/// # Safety
+///
+/// Every byte of every value of the type must be initialized.
+/// For a struct, that means `#[repr(C)]`, with fields that
+/// are `NoUninit` too and that leave no gaps between them.
+pub unsafe trait NoUninit: Copy {}
+
+macro_rules! no_uninit {
+    ($($t:ty),*) => { $(unsafe impl NoUninit for $t {})* };
+}
+
+no_uninit!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, ());
+
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+pub struct SeqLock<T> {
+    /// Odd while the writer is writing.
+    seq: AtomicUsize,
+    /// Whether there's a `Writer`.
+    has_writer: AtomicBool,
+    /// The bytes of the `T`.
+    data: Box<[AtomicU8]>,
+    _value: PhantomData<T>,
+}
+
+// Readers get copies of the value that the writer wrote on another thread.
+unsafe impl<T: Send> Sync for SeqLock<T> {}
+
+/// The only one that can write to a `SeqLock`, until it's dropped.
+pub struct Writer<'a, T: NoUninit> {
+    lock: &'a SeqLock<T>,
+}
+
+impl<T: NoUninit> SeqLock<T> {
+    pub fn new(value: T) -> Self {
+        let lock = Self {
+            seq: AtomicUsize::new(0),
+            has_writer: AtomicBool::new(false),
+            data: (0..size_of::<T>()).map(|_| AtomicU8::new(0)).collect(),
+            _value: PhantomData,
+        };
+        lock.store(&value);
+        lock
+    }
+
+    /// Copies the value, retrying as long as the writer is writing it. Never blocks the writer.
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Copies the value, unless the writer was writing it in the meantime.
+    pub fn try_read(&self) -> Option<T> {
+        // Acquire, to see everything the writer wrote before it made this even.
+        let seq = self.seq.load(Acquire);
+        if seq % 2 == 1 {
+            return None;
+        }
+        let value = self.load();
+        // If any of the loads above saw a byte of a later write, this makes
+        // the writer's fence, and so the odd sequence number from before that
+        // write, happen before the load below. Like the fence example in chapter 3,
+        // but with the acquire fence after relaxed loads of the data itself.
+        fence(Acquire);
+        if self.seq.load(Relaxed) != seq {
+            return None;
+        }
+        // Safety: Nothing wrote to it while we copied it, so
+        // these are all the bytes of the same `T`.
+        Some(unsafe { value.assume_init() })
+    }
+
+    /// The writer, unless there already is one.
+    pub fn try_writer(&self) -> Option<Writer<'_, T>> {
+        // Acquire, to see everything the previous writer wrote.
+        if self.has_writer.swap(true, Acquire) {
+            return None;
+        }
+        Some(Writer { lock: self })
+    }
+
+    /// The writer. Panics if there already is one.
+    pub fn writer(&self) -> Writer<'_, T> {
+        self.try_writer().expect("SeqLock already has a writer")
+    }
+
+    pub fn into_inner(self) -> T {
+        // Safety: Nobody else is writing, so these are all the bytes of the same `T`.
+        unsafe { self.load().assume_init() }
+    }
+
+    fn store(&self, value: &T) {
+        // Safety: `T: NoUninit`, so all its bytes are initialized.
+        let bytes = unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        for (byte, &b) in self.data.iter().zip(bytes) {
+            byte.store(b, Relaxed);
+        }
+    }
+
+    fn load(&self) -> MaybeUninit<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = value.as_mut_ptr() as *mut u8;
+        for (i, byte) in self.data.iter().enumerate() {
+            // Safety: `data` is exactly as long as a `T`.
+            unsafe { bytes.add(i).write(byte.load(Relaxed)) };
+        }
+        value
+    }
+}
+
+impl<T: NoUninit> Writer<'_, T> {
+    /// The value as we last wrote it.
+    pub fn get(&self) -> T {
+        // Safety: Only we write to it.
+        unsafe { self.lock.load().assume_init() }
+    }
+
+    pub fn write(&mut self, value: T) {
+        let lock = self.lock;
+        // Relaxed: only we change it.
+        let seq = lock.seq.load(Relaxed);
+        lock.seq.store(seq + 1, Relaxed);
+        // Makes the odd sequence number visible to any reader that sees any byte we store below.
+        // (Together with the acquire fence in `try_read`.)
+        fence(Release);
+        lock.store(&value);
+        // Release, for a reader that sees it to see all the bytes.
+        lock.seq.store(seq + 2, Release);
+    }
+
+    /// Changes the value in place, as a single write.
+    pub fn update(&mut self, f: impl FnOnce(&mut T)) {
+        let mut value = self.get();
+        f(&mut value);
+        self.write(value);
+    }
+}
+
+impl<T: NoUninit> Drop for Writer<'_, T> {
+    fn drop(&mut self) {
+        // Release, for the next writer to see what we wrote.
+        self.lock.has_writer.store(false, Release);
+    }
+}
+
+/// For tests: like the statistics in `examples/ch2-07-statistics.rs`.
+#[cfg(test)]
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Stats {
+    num_done: u64,
+    total_time: u64,
+    max_time: u64,
+}
+
+#[cfg(test)]
+unsafe impl NoUninit for Stats {}
+
+#[test]
+fn main() {
+    let lock = SeqLock::new([1u16, 2, 3]);
+    assert_eq!(lock.read(), [1, 2, 3]);
+    let mut w = lock.writer();
+    assert!(lock.try_writer().is_none());
+    w.write([4, 5, 6]);
+    assert_eq!(lock.try_read(), Some([4, 5, 6]));
+    w.update(|v| v[0] = 7);
+    assert_eq!(w.get(), [7, 5, 6]);
+    assert_eq!(lock.read(), [7, 5, 6]);
+    // Two writes, two increments of two.
+    assert_eq!(lock.seq.load(Relaxed), 4);
+    drop(w);
+    lock.writer().write([8, 9, 10]);
+    assert_eq!(lock.into_inner(), [8, 9, 10]);
+
+    // Zero-sized types have nothing to copy.
+    SeqLock::new(()).read();
+}
+
+#[test]
+fn statistics() {
+    use std::thread;
+
+    let stats = SeqLock::new(Stats::default());
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| loop {
+                let Stats { num_done: n, total_time, max_time } = stats.read();
+                // Never some of the fields from one write and some from another.
+                assert_eq!(total_time, n * (n + 1) / 2);
+                assert_eq!(max_time, n);
+                if n == 10_000 {
+                    break;
+                }
+            });
+        }
+        let mut w = stats.writer();
+        for time_taken in 1..=10_000 {
+            w.update(|s| {
+                s.num_done += 1;
+                s.total_time += time_taken;
+                s.max_time = s.max_time.max(time_taken);
+            });
+        }
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom() {
+    use crate::sync::{thread, Arc};
+
+    // Every interleaving takes more than five minutes. A torn read takes two preemptions:
+    // the reader switched out between the two bytes, and back in once the writer wrote
+    // the second one. Four also leave room for that to happen in the second write.
+    crate::sync::model_bounded(4, || {
+        let lock = Arc::new(SeqLock::new([0u8; 2]));
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let mut w = lock.writer();
+                w.write([1, 1]);
+                w.write([2, 2]);
+            })
+        };
+        let a = lock.read();
+        let b = lock.read();
+        assert!(a[0] == a[1] && b[0] == b[1] && a[0] <= b[0]);
+        t.join().unwrap();
+    });
+}
@@ -0,0 +1,424 @@
+//! A concurrent hash map made of a fixed number of `rwlock_3::RwLock<HashMap>` shards.
+//!
+//! Every key always goes to the same shard, picked by its hash, so the shards never need
+//! to be rebalanced: each one is just a `HashMap` that grows by itself. Threads only contend
+//! when they use the same shard at the same time, and readers of a shard don't block each other.
+//!
+//! Unlike a lock-free map like papaya, values don't need to be reclaimed later, so there's
+//! nothing to pin. `pin()` exists anyway, so code written for papaya's `map.pin().get(..)`
+//! works unchanged, and `concurrent_map` has traits for code that works with either map.
+//! What's different is that `get` and friends return a guard rather than a plain
+//! reference, which keeps the shard locked. Using the same map again while
+//! holding one might deadlock, if the key happens to go to the same shard.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::{self, HashMap, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::sync::PoisonError;
+use crate::cache_padded::CachePadded;
+use crate::ch9_locks::rwlock_3::{ReadGuard, RwLock, WriteGuard};
+
+type Shard<K, V, S> = CachePadded<RwLock<HashMap<K, V, S>>>;
+
+pub struct ShardedMap<K, V, S = RandomState> {
+    /// A power of two of them.
+    shards: Box<[Shard<K, V, S>]>,
+    /// 64 minus the number of bits of the hash needed to pick a shard.
+    shift: u32,
+    /// Also cloned into every shard, so the hash we pick a shard with is the
+    /// same one the shard's `HashMap` uses. See `shard_index`.
+    hasher: S,
+}
+
+/// A `ShardedMap`, ready to be used like a pinned papaya map.
+pub struct Pinned<'a, K, V, S = RandomState> {
+    map: &'a ShardedMap<K, V, S>,
+}
+
+/// A reference to a value in the map. Keeps its shard locked for reading.
+pub struct Ref<'a, K, V, S = RandomState> {
+    _guard: ReadGuard<'a, HashMap<K, V, S>>,
+    /// Points into the `HashMap` behind `_guard`, which can't change while we hold it.
+    value: *const V,
+}
+
+/// A mutable reference to a value in the map. Keeps its shard locked for writing.
+pub struct RefMut<'a, K, V, S = RandomState> {
+    _guard: WriteGuard<'a, HashMap<K, V, S>>,
+    /// Points into the `HashMap` behind `_guard`, which only we can change while we hold it.
+    value: *mut V,
+}
+
+/// A key's place in the map, which might or might not have a value.
+/// Keeps its shard locked for writing.
+pub struct Entry<'a, K, V, S = RandomState> {
+    guard: WriteGuard<'a, HashMap<K, V, S>>,
+    key: K,
+}
+
+impl<K, V> ShardedMap<K, V> {
+    /// A map with four shards per available core.
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(cores * 4)
+    }
+
+    /// A map with `n` shards, rounded up to a power of two.
+    pub fn with_shards(n: usize) -> Self {
+        Self::with_shards_and_hasher(n, RandomState::new())
+    }
+}
+
+impl<K, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S: Clone> ShardedMap<K, V, S> {
+    /// A map with `n` shards, rounded up to a power of two, that hashes keys with `hasher`.
+    pub fn with_shards_and_hasher(n: usize, hasher: S) -> Self {
+        let n = n.max(1).next_power_of_two();
+        let shards = (0..n)
+            .map(|_| CachePadded::new(RwLock::new(HashMap::with_hasher(hasher.clone()))))
+            .collect();
+        Self { shards, shift: 64 - n.trailing_zeros(), hasher }
+    }
+}
+
+impl<K, V, S> ShardedMap<K, V, S> {
+    /// For writing code the same way as with papaya. Doesn't do anything else.
+    pub fn pin(&self) -> Pinned<'_, K, V, S> {
+        Pinned { map: self }
+    }
+
+    /// The number of entries in all shards together.
+    ///
+    /// The shards are counted one by one, so with other threads inserting
+    /// and removing at the same time, this might not be the size the map
+    /// ever had at any single point in time.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.read_shard(i).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|i| self.read_shard(i).is_empty())
+    }
+
+    /// Calls `f` for every entry, locking one shard at a time.
+    ///
+    /// Just like `len`, this isn't a snapshot: an entry moved from one key to another
+    /// while we're going through the shards might be seen twice, or not at all.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        for i in 0..self.shards.len() {
+            for (k, v) in self.read_shard(i).iter() {
+                f(k, v);
+            }
+        }
+    }
+
+    /// Clones of all entries, taken one shard at a time, like `for_each`.
+    ///
+    /// Every shard is only locked for as long as it takes to clone its entries.
+    pub fn iter(&self) -> Iter<'_, K, V, S>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Iter { map: self, next_shard: 0, entries: Vec::new().into_iter() }
+    }
+
+    // A panic while a shard is locked, such as in `update_or_insert_with`'s `update`,
+    // leaves that shard a perfectly fine `HashMap`, so we ignore poisoning.
+
+    fn read_shard(&self, i: usize) -> ReadGuard<'_, HashMap<K, V, S>> {
+        self.shards[i].read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_shard(&self, i: usize) -> WriteGuard<'_, HashMap<K, V, S>> {
+        self.shards[i].write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ShardedMap<K, V, S> {
+    /// The shard for a key: the bits of its hash right below the top seven.
+    ///
+    /// The `HashMap` in the shard uses the lowest bits of the same hash to pick a bucket,
+    /// and the top seven as a tag to quickly skip non-matching keys. All keys in a shard
+    /// have the bits we pick in common, so using those for either would be a waste.
+    fn shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        let hash = self.hasher.hash_one(key);
+        (hash << 7).checked_shr(self.shift).unwrap_or(0) as usize
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let guard = self.read_shard(self.shard_index(key));
+        let value: *const V = guard.get(key)?;
+        Some(Ref { _guard: guard, value })
+    }
+
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut guard = self.write_shard(self.shard_index(key));
+        let value: *mut V = guard.get_mut(key)?;
+        Some(RefMut { _guard: guard, value })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read_shard(self.shard_index(key)).contains_key(key)
+    }
+
+    /// Returns the old value, if there was one.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write_shard(self.shard_index(&key)).insert(key, value)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write_shard(self.shard_index(key)).remove(key)
+    }
+
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S> {
+        Entry { guard: self.write_shard(self.shard_index(&key)), key }
+    }
+
+    /// Replaces the value with `update` of it, or inserts `insert()` if there's none.
+    /// Returns the new value.
+    ///
+    /// Unlike with papaya, `update` is only called once, since nobody
+    /// else can change the value while we hold the shard's lock.
+    pub fn update_or_insert_with(
+        &self,
+        key: K,
+        update: impl FnOnce(&V) -> V,
+        insert: impl FnOnce() -> V,
+    ) -> Ref<'_, K, V, S> {
+        let mut guard = self.write_shard(self.shard_index(&key));
+        let value: *const V = match guard.entry(key) {
+            hash_map::Entry::Occupied(mut e) => {
+                let new = update(e.get());
+                e.insert(new);
+                e.into_mut()
+            }
+            hash_map::Entry::Vacant(e) => e.insert(insert()),
+        };
+        // Nobody can get in between, so the value is still there.
+        Ref { _guard: guard.downgrade(), value }
+    }
+
+    /// `update_or_insert_with`, with a value to insert that's already there.
+    pub fn update_or_insert(&self, key: K, update: impl FnOnce(&V) -> V, value: V) -> Ref<'_, K, V, S> {
+        self.update_or_insert_with(key, update, || value)
+    }
+}
+
+impl<K, V, S> Deref for Pinned<'_, K, V, S> {
+    type Target = ShardedMap<K, V, S>;
+    fn deref(&self) -> &ShardedMap<K, V, S> {
+        self.map
+    }
+}
+
+impl<K, V, S> Deref for Ref<'_, K, V, S> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        // Safety: We hold a read lock on the map it's in.
+        unsafe { &*self.value }
+    }
+}
+
+impl<K, V, S> Deref for RefMut<'_, K, V, S> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        // Safety: We hold a write lock on the map it's in.
+        unsafe { &*self.value }
+    }
+}
+
+impl<K, V, S> DerefMut for RefMut<'_, K, V, S> {
+    fn deref_mut(&mut self) -> &mut V {
+        // Safety: We hold a write lock on the map it's in.
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.guard.get(&self.key)
+    }
+
+    /// Modifies the value, if there is one.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Self {
+        if let Some(v) = self.guard.get_mut(&self.key) {
+            f(v);
+        }
+        self
+    }
+
+    pub fn or_insert(self, value: V) -> RefMut<'a, K, V, S> {
+        self.or_insert_with(|| value)
+    }
+
+    pub fn or_insert_with(mut self, f: impl FnOnce() -> V) -> RefMut<'a, K, V, S> {
+        let value: *mut V = self.guard.entry(self.key).or_insert_with(f);
+        RefMut { _guard: self.guard, value }
+    }
+
+    pub fn or_default(self) -> RefMut<'a, K, V, S>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+/// Clones of the entries of a `ShardedMap`, from `ShardedMap::iter`.
+pub struct Iter<'a, K, V, S = RandomState> {
+    map: &'a ShardedMap<K, V, S>,
+    next_shard: usize,
+    /// The rest of the last shard we cloned.
+    entries: std::vec::IntoIter<(K, V)>,
+}
+
+impl<K: Clone, V: Clone, S> Iterator for Iter<'_, K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(entry);
+            }
+            if self.next_shard == self.map.shards.len() {
+                return None;
+            }
+            let shard = self.map.read_shard(self.next_shard);
+            self.entries = shard.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>().into_iter();
+            self.next_shard += 1;
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for ShardedMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        self.for_each(|k, v| {
+            map.entry(k, v);
+        });
+        map.finish()
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for Pinned<'_, K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<K, V: fmt::Debug, S> fmt::Debug for Ref<'_, K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<K, V: fmt::Debug, S> fmt::Debug for RefMut<'_, K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[test]
+fn main() {
+    let map = ShardedMap::with_shards(4);
+    assert!(map.is_empty());
+    assert_eq!(map.insert("a", 1), None);
+    assert_eq!(map.insert("a", 2), Some(1));
+    assert_eq!(*map.get("a").unwrap(), 2);
+    assert!(map.get("b").is_none());
+    *map.get_mut("a").unwrap() += 1;
+    assert_eq!(*map.get("a").unwrap(), 3);
+
+    // Entries.
+    *map.entry("b").or_insert(10) += 1;
+    *map.entry("b").and_modify(|v| *v *= 2).or_insert(0) += 1;
+    assert_eq!(*map.entry("c").or_default(), 0);
+    assert_eq!(map.entry("b").get(), Some(&23));
+
+    assert_eq!(*map.update_or_insert_with("d", |v| v + 1, || 100), 100);
+    assert_eq!(*map.update_or_insert("d", |v| v + 1, 100), 101);
+    assert_eq!(map.len(), 4);
+    assert!(map.contains_key("d"));
+    assert_eq!(map.remove("d"), Some(101));
+    assert_eq!(map.remove("d"), None);
+
+    let mut all = map.iter().collect::<Vec<_>>();
+    all.sort();
+    assert_eq!(all, [("a", 3), ("b", 23), ("c", 0)]);
+    let mut sum = 0;
+    map.for_each(|_, v| sum += v);
+    assert_eq!(sum, 26);
+    assert_eq!(format!("{:?}", map.get("a").unwrap()), "3");
+
+    // A single shard works just the same.
+    let map = ShardedMap::with_shards(1);
+    map.insert(1, 1);
+    assert_eq!(format!("{map:?}"), "{1: 1}");
+}
+
+#[test]
+fn shards() {
+    let map = ShardedMap::with_shards(6);
+    assert_eq!(map.shards.len(), 8);
+    for i in 0..1000 {
+        map.insert(i, i);
+    }
+    // Spread over all shards, none of which has more than twice its share.
+    for i in 0..8 {
+        let n = map.read_shard(i).len();
+        assert!(n > 0 && n < 250, "shard {i} has {n} entries");
+    }
+    assert_eq!(map.iter().count(), 1000);
+}
+
+#[test]
+fn concurrent() {
+    use std::thread;
+
+    let map = ShardedMap::new();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let map = &map;
+            s.spawn(move || {
+                for i in 0..1000 {
+                    map.insert((t, i), i);
+                    // Every thread counts in the same place.
+                    map.update_or_insert_with((usize::MAX, 0), |n| n + 1, || 1);
+                    if i % 2 == 0 {
+                        assert_eq!(map.remove(&(t, i)), Some(i));
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(map.remove(&(usize::MAX, 0)), Some(4000));
+    assert_eq!(map.len(), 2000);
+}
@@ -0,0 +1,341 @@
+//! The atomics, `UnsafeCell`, threads and locks that `ch4_spin_lock`, `ch5_channels`,
+//! `ch6_arc`, `ch9_locks`, `futex`, `left_right` and `seqlock` are built on.
+//!
+//! Normally, these are simply the ones from `std`. With `--cfg loom`, they're the ones
+//! from [loom](https://docs.rs/loom) instead, and `futex` waits and wakes using a loom
+//! `Mutex` and `Condvar`. Loom runs a test over and over again, once for every way its threads
+//! can interleave, and for every value every atomic load is allowed to see under the
+//! memory model. It fails if any of those runs panics, deadlocks, or touches the contents
+//! of an `UnsafeCell` without that happening after all other accesses to it.
+//!
+//! That's exhaustive for most tests here, which use `model`. A few would take minutes
+//! or hours, and use `model_bounded` to only try the interleavings with a few preemptions.
+//! Those only find bugs that take no more preemptions than that to show up, and each
+//! of them explains why that should be enough for the races it's after. Apart from
+//! that, loom doesn't model everything the memory model allows, as explained below.
+//!
+//! Loom's atomics and `UnsafeCell` can't be created in a `const fn`, so under loom,
+//! the constructors that create them aren't `const`. See [`const_fn`]. Loom's `UnsafeCell`
+//! has no `get`, since it needs to know whether an access is a read or a write, so ours
+//! has `get`, which counts as a write, and `get_const`, which counts as a read.
+//!
+//! Loom only models the total order of `SeqCst` operations for `fence(SeqCst)`, so
+//! code that relies on it uses fences. And loom doesn't know that a read-modify-write
+//! operation comes right after the value it read in the modification order, so a later
+//! plain store by another thread isn't necessarily ordered after it. A loop that keeps
+//! swapping in the same value (such as `true`, to lock a spin lock) can then keep
+//! reading its own previous swap forever, instead of the store that unlocked it.
+//! So the tests of such loops never let one race that store: the locks are checked
+//! without preemptions, so one thread only locks once the other has unlocked (which
+//! still checks that it sees everything the other did), and the channels only try to
+//! receive once while the message is being sent.
+//!
+//! Run all loom tests with:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+
+#[cfg(not(loom))]
+pub(crate) use std_imp::*;
+
+/// Defines a `const fn`, or, under loom, the same function without the `const`.
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis const fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])* $vis const fn $($rest)*
+
+        #[cfg(loom)]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+
+pub(crate) use const_fn;
+
+#[cfg(loom)]
+pub(crate) use loom_imp::*;
+
+#[cfg(not(loom))]
+mod std_imp {
+    pub(crate) use std::sync::atomic;
+    pub(crate) use std::sync::{Arc, Condvar, Mutex};
+    pub(crate) use std::{hint, thread, thread_local};
+
+    #[repr(transparent)]
+    pub(crate) struct UnsafeCell<T: ?Sized>(std::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub const fn new(value: T) -> Self {
+            Self(std::cell::UnsafeCell::new(value))
+        }
+    }
+
+    impl<T: ?Sized> UnsafeCell<T> {
+        /// For writing (or reading) the value.
+        #[inline(always)]
+        pub fn get(&self) -> *mut T {
+            self.0.get()
+        }
+
+        /// For only reading the value, while other threads might be reading it too.
+        #[inline(always)]
+        pub fn get_const(&self) -> *const T {
+            self.0.get()
+        }
+
+        #[inline(always)]
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut()
+        }
+
+        #[inline(always)]
+        pub fn raw_get(this: *const Self) -> *mut T {
+            std::cell::UnsafeCell::raw_get(this as *const std::cell::UnsafeCell<T>)
+        }
+    }
+}
+
+#[cfg(loom)]
+mod loom_imp {
+    use std::ops::Deref;
+
+    pub(crate) use loom::sync::{Arc, Condvar, Mutex};
+    pub(crate) use loom::{hint, thread, thread_local};
+
+    pub(crate) mod atomic {
+        pub(crate) use loom::sync::atomic::fence;
+        pub(crate) use std::sync::atomic::Ordering;
+
+        pub type AtomicBool = super::Atomic<loom::sync::atomic::AtomicBool>;
+        pub type AtomicU8 = super::Atomic<loom::sync::atomic::AtomicU8>;
+        pub type AtomicU32 = super::Atomic<loom::sync::atomic::AtomicU32>;
+        pub type AtomicU64 = super::Atomic<loom::sync::atomic::AtomicU64>;
+        pub type AtomicUsize = super::Atomic<loom::sync::atomic::AtomicUsize>;
+        pub type AtomicPtr<T> = super::Atomic<loom::sync::atomic::AtomicPtr<T>>;
+    }
+
+    /// Loom runs all threads of a test on the same OS thread, one at a time,
+    /// so nothing in here is ever touched by two threads at once.
+    struct Lazy<T>(std::cell::UnsafeCell<T>);
+
+    unsafe impl<T> Sync for Lazy<T> {}
+    unsafe impl<T: Send> Send for Lazy<T> {}
+
+    pub trait LoomAtomic {
+        type Value: Copy;
+        fn new(value: Self::Value) -> Self;
+        /// Panics if not all stores happen before this.
+        fn unsync_load(&mut self) -> Self::Value;
+    }
+
+    macro_rules! loom_atomic {
+        ($($t:ty => $v:ty,)*) => {$(
+            impl LoomAtomic for $t {
+                type Value = $v;
+                fn new(value: $v) -> Self {
+                    <$t>::new(value)
+                }
+                fn unsync_load(&mut self) -> $v {
+                    // Safety: We have exclusive access.
+                    unsafe { <$t>::unsync_load(self) }
+                }
+            }
+        )*};
+    }
+
+    loom_atomic! {
+        loom::sync::atomic::AtomicBool => bool,
+        loom::sync::atomic::AtomicU8 => u8,
+        loom::sync::atomic::AtomicU32 => u32,
+        loom::sync::atomic::AtomicU64 => u64,
+        loom::sync::atomic::AtomicUsize => usize,
+    }
+
+    impl<T> LoomAtomic for loom::sync::atomic::AtomicPtr<T> {
+        type Value = *mut T;
+        fn new(value: *mut T) -> Self {
+            Self::new(value)
+        }
+        fn unsync_load(&mut self) -> *mut T {
+            // Safety: We have exclusive access.
+            unsafe { loom::sync::atomic::AtomicPtr::unsync_load(self) }
+        }
+    }
+
+    enum AtomicState<L: LoomAtomic> {
+        Loom(L),
+        /// Since the last `get_mut`.
+        Value(L::Value),
+    }
+
+    /// A loom atomic with a `get_mut`, which loom's don't have.
+    /// Derefs to the loom atomic, for everything else.
+    pub struct Atomic<L: LoomAtomic>(Lazy<AtomicState<L>>);
+
+    // Like the std atomics, also for `AtomicPtr`.
+    unsafe impl<L: LoomAtomic> Send for Atomic<L> {}
+
+    impl<L: LoomAtomic> Atomic<L> {
+        pub fn new(value: L::Value) -> Self {
+            Self(Lazy(std::cell::UnsafeCell::new(AtomicState::Loom(L::new(value)))))
+        }
+
+        /// Replaces the loom atomic by its value. The next use creates a new one,
+        /// with whatever value was left through this reference. That counts as
+        /// a write by that thread, which is fine, since `get_mut` is mostly used by `Drop`.
+        pub fn get_mut(&mut self) -> &mut L::Value {
+            let state = self.0 .0.get_mut();
+            if let AtomicState::Loom(l) = state {
+                *state = AtomicState::Value(l.unsync_load());
+            }
+            match state {
+                AtomicState::Value(v) => v,
+                AtomicState::Loom(_) => unreachable!(),
+            }
+        }
+
+        pub fn into_inner(mut self) -> L::Value {
+            *self.get_mut()
+        }
+    }
+
+    impl<L: LoomAtomic> Deref for Atomic<L> {
+        type Target = L;
+        fn deref(&self) -> &L {
+            let state = self.0 .0.get();
+            // Safety: See `Lazy`. The loom atomic is only
+            // replaced by `get_mut`, which can't happen while it's borrowed.
+            unsafe {
+                if let AtomicState::Value(v) = *state {
+                    *state = AtomicState::Loom(L::new(v));
+                }
+                match &*state {
+                    AtomicState::Loom(l) => l,
+                    AtomicState::Value(_) => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Tracks all accesses to the value with a loom `UnsafeCell<()>`.
+    // repr(C), so the (maybe unsized) value goes last, and `ch6_arc::s3_optimized`
+    // can find it. (It doesn't take over-aligned values into account, though.)
+    #[repr(C)]
+    pub(crate) struct UnsafeCell<T: ?Sized> {
+        tracker: loom::cell::UnsafeCell<()>,
+        value: std::cell::UnsafeCell<T>,
+    }
+
+    impl<T> UnsafeCell<T> {
+        pub fn new(value: T) -> Self {
+            Self { tracker: loom::cell::UnsafeCell::new(()), value: std::cell::UnsafeCell::new(value) }
+        }
+    }
+
+    impl<T: ?Sized> UnsafeCell<T> {
+        /// Counts as a write.
+        pub fn get(&self) -> *mut T {
+            self.tracker.with_mut(|_| ());
+            self.value.get()
+        }
+
+        /// Counts as a read.
+        pub fn get_const(&self) -> *const T {
+            self.tracker.with(|_| ());
+            self.value.get()
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.tracker.with_mut(|_| ());
+            self.value.get_mut()
+        }
+
+        pub fn raw_get(this: *const Self) -> *mut T {
+            // Not tracked: this is only used to get a pointer that's used much later.
+            unsafe { std::cell::UnsafeCell::raw_get(std::ptr::addr_of!((*this).value)) }
+        }
+    }
+}
+
+/// Runs a loom model of `f`, exploring every interleaving,
+/// unless `LOOM_MAX_PREEMPTIONS` sets a bound.
+#[cfg(all(loom, test))]
+pub(crate) fn model(f: impl Fn() + Sync + Send + 'static) {
+    loom::model::Builder::new().check(f);
+}
+
+/// Like `model`, but only explores the interleavings with up to `preemptions` points where
+/// a thread that could have kept running is switched out, unless `LOOM_MAX_PREEMPTIONS`
+/// says otherwise. For models that would take too long otherwise, so not exhaustive:
+/// every test that uses this says why its bound is enough for what it checks.
+#[cfg(all(loom, test))]
+pub(crate) fn model_bounded(preemptions: usize, f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound.get_or_insert(preemptions);
+    builder.check(f);
+}
+
+/// Checks that `lock` keeps two threads from incrementing its counter at the same
+/// time, and that the second one sees the first one's increment, with `increment`
+/// locking it and incrementing the counter, and `get` locking it and returning it.
+#[cfg(all(loom, test))]
+pub(crate) fn check_lock<L: Send + Sync + 'static>(
+    lock: fn() -> L,
+    increment: fn(&L),
+    get: fn(&L) -> u32,
+) {
+    model(move || increment_twice(lock, increment, get));
+}
+
+/// `check_lock`, with `model_bounded`.
+#[cfg(all(loom, test))]
+pub(crate) fn check_lock_bounded<L: Send + Sync + 'static>(
+    preemptions: usize,
+    lock: fn() -> L,
+    increment: fn(&L),
+    get: fn(&L) -> u32,
+) {
+    model_bounded(preemptions, move || increment_twice(lock, increment, get));
+}
+
+#[cfg(all(loom, test))]
+fn increment_twice<L: Send + Sync + 'static>(lock: fn() -> L, increment: fn(&L), get: fn(&L) -> u32) {
+    let l = Arc::new(lock());
+    let t = thread::spawn({
+        let l = l.clone();
+        move || increment(&l)
+    });
+    increment(&l);
+    t.join().unwrap();
+    assert_eq!(get(&l), 2);
+}
+
+/// Data for loom tests of `Arc`s. Dropping it counts as writing to it, so a model
+/// fails if that might happen while another thread could still be reading it.
+#[cfg(all(loom, test))]
+pub(crate) struct Tracked(UnsafeCell<u32>);
+
+#[cfg(all(loom, test))]
+unsafe impl Sync for Tracked {}
+
+#[cfg(all(loom, test))]
+impl Tracked {
+    pub fn new(value: u32) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    pub fn get(&self) -> u32 {
+        unsafe { *self.0.get_const() }
+    }
+
+    pub fn set(&mut self, value: u32) {
+        *self.0.get_mut() = value;
+    }
+}
+
+#[cfg(all(loom, test))]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}